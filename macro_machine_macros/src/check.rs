@@ -0,0 +1,164 @@
+// Validation of State and Command references. Errors point to the offending tokens of the machine declaration,
+// so they are reported instead of errors in generated code.
+
+use syn::{Error, Ident, Result};
+
+use crate::parse::{Body, Command, Machine, Region, Target};
+
+// Items of machine module which State context structs would clash with. Region modules declare them too.
+const RESERVED_STATES: &[&str] = &[
+    "Analysis", "CanDoJob", "CommandId", "Commands", "Deferral", "Error", "Machine", "MachineContext", "Observer",
+    "StateId", "States", "Target", "Timeout", "Transition", "UserError",
+];
+// Items of machine with regions which region modules would clash with. Region is also a field of Machine and
+// the name of its accessor, so fields and methods of Machine and fields of Analysis are reserved too.
+const RESERVED_REGIONS: &[&str] = &[
+    "Analysis", "CommandId", "Commands", "Error", "Machine", "MachineContext", "RegionId",
+    "analyze", "available_commands", "clock", "context", "defer", "dispatch", "execute", "fail", "from_snapshot",
+    "get_inner_context", "inner_context", "inner_context_mut", "is_deferred", "is_joined", "next_deadline", "now",
+    "pending", "poll", "replayed", "run_to_completion", "start", "to_dot", "to_mermaid", "to_plantuml",
+    "unused_commands", "with_clock",
+];
+
+struct Errors(Option<Error>);
+
+impl Errors {
+    fn push(&mut self, ident: &Ident, message: String) {
+        let error = Error::new(ident.span(), message);
+        match self.0 {
+            Some(ref mut errors) => errors.combine(error),
+            None => self.0 = Some(error),
+        }
+    }
+}
+
+pub fn check(machine: &Machine) -> Result<()> {
+    let mut errors = Errors(None);
+    check_commands(&mut errors, &machine.commands);
+    match machine.body {
+        Body::Single(ref region) => check_region(&mut errors, region, &machine.commands),
+        Body::Regions{ref regions, ..} => for region in regions {
+            if RESERVED_REGIONS.iter().any(|reserved| region.name == reserved) {
+                errors.push(&region.name, format!("region name `{}` is reserved by generated machine, rename the region", region.name));
+            }
+            check_region(&mut errors, region, &machine.commands);
+        },
+    }
+    match errors.0 {
+        Some(error) => Err(error),
+        None => Ok(()),
+    }
+}
+
+fn check_commands(errors: &mut Errors, commands: &[Command]) {
+    for (i, command) in commands.iter().enumerate() {
+        if commands[..i].iter().any(|previous| previous.name == command.name) {
+            errors.push(&command.name, format!("command `{}` is declared in commands[...] more than once", command.name));
+        }
+    }
+}
+
+fn check_region(errors: &mut Errors, region: &Region, commands: &[Command]) {
+    let declared = |state: &Ident| region.states.iter().any(|declared| declared.name == *state);
+
+    for (i, state) in region.states.iter().enumerate() {
+        if region.states[..i].iter().any(|previous| previous.name == state.name) {
+            errors.push(&state.name, format!("state `{}` is declared in states[...] more than once", state.name));
+        }
+        if RESERVED_STATES.iter().any(|reserved| state.name == reserved) {
+            errors.push(&state.name, format!("state name `{}` is reserved by generated machine, rename the state", state.name));
+        }
+        if let Some(ref parent) = state.parent {
+            if !declared(parent) {
+                errors.push(parent, format!("parent state `{}` of state `{}` is not declared in states[...]", parent, state.name));
+            }
+        }
+        // Chain of parents is walked until it leaves declared states or returns to the state.
+        let mut chain = vec![&state.name];
+        let mut parent = state.parent.as_ref();
+        while let Some(current) = parent {
+            chain.push(current);
+            if *current == state.name {
+                let chain: Vec<String> = chain.iter().map(|state| state.to_string()).collect();
+                errors.push(&state.name, format!("parents of state `{}` form a cycle: {}", state.name, chain.join(" -> ")));
+                break;
+            }
+            if chain.len() > region.states.len() {
+                break;
+            }
+            parent = region.states.iter().find(|declared| declared.name == *current).and_then(|declared| declared.parent.as_ref());
+        }
+        if !region.nodes.iter().any(|node| node.state == state.name) {
+            errors.push(&state.name, format!("state `{}` has no state node", state.name));
+        }
+    }
+    if !declared(&region.initial.state) {
+        errors.push(&region.initial.state, format!("initial state `{}` is not declared in states[...]", region.initial.state));
+    }
+    if let Some(ref error) = region.error {
+        if !declared(&error.state.state) {
+            errors.push(&error.state.state, format!("error state `{}` is not declared in states[...]", error.state.state));
+        }
+    }
+    for state in &region.finals {
+        if !declared(state) {
+            errors.push(state, format!("final state `{}` is not declared in states[...]", state));
+        }
+    }
+
+    for (i, node) in region.nodes.iter().enumerate() {
+        if !declared(&node.state) {
+            errors.push(&node.state, format!("state node `{}` is not declared in states[...]", node.state));
+        } else if region.nodes[..i].iter().any(|previous| previous.state == node.state) {
+            errors.push(&node.state, format!("state `{}` has more than one state node", node.state));
+        }
+        // Parent state has no context: its callbacks and reactions are executed on a fresh instance.
+        if region.states.iter().any(|state| state.parent.as_ref() == Some(&node.state)) {
+            for field in &node.fields {
+                errors.push(&field.name, format!("parent state `{}` can't have context field `{}`", node.state, field.name));
+            }
+        }
+        for (j, reaction) in node.reactions.iter().enumerate() {
+            match commands.iter().find(|command| command.name == reaction.command) {
+                Some(command) => for binding in &reaction.bindings {
+                    let fields = command.fields.as_ref().map(|fields| &fields[..]).unwrap_or(&[]);
+                    if !fields.iter().any(|field| field.name == *binding) {
+                        errors.push(binding, format!("command `{}` has no field `{}`", command.name, binding));
+                    }
+                },
+                None => errors.push(&reaction.command, format!("command `{}` in reaction of state `{}` is not declared in commands[...]", reaction.command, node.state)),
+            }
+            match reaction.target {
+                Target::State(ref target) => if !declared(&target.state) {
+                    errors.push(&target.state, format!("state `{}` in reaction of state `{}` is not declared in states[...]", target.state, node.state));
+                },
+                // Without history there is no state to return to, so reaction would never be executed.
+                Target::Back(ref back) => if region.history.is_none() {
+                    errors.push(back, format!("reaction on command `{}` in state `{}` moves back, but history(N) is not declared", reaction.command, node.state));
+                },
+                Target::Stay => {},
+            }
+            // Reaction on the same Command after reaction without guard is never executed. Reaction which moves
+            // machine back has implicit guard.
+            let shadowed = node.reactions[..j].iter().any(|previous| {
+                previous.command == reaction.command && previous.guard.is_none() && !matches!(previous.target, Target::Back(_))
+            });
+            if shadowed {
+                errors.push(&reaction.command, format!("reaction on command `{}` in state `{}` is unreachable: previous reaction on it has no guard", reaction.command, node.state));
+            }
+        }
+        for command in &node.defers {
+            if !commands.iter().any(|declared| declared.name == *command) {
+                errors.push(command, format!("command `{}` in defer of state `{}` is not declared in commands[...]", command, node.state));
+            }
+        }
+        for (j, timeout) in node.timeouts.iter().enumerate() {
+            if j > 0 {
+                errors.push(&timeout.target.state, format!("state `{}` has more than one timeout", node.state));
+            }
+            if !declared(&timeout.target.state) {
+                errors.push(&timeout.target.state, format!("state `{}` in timeout of state `{}` is not declared in states[...]", timeout.target.state, node.state));
+            }
+        }
+    }
+}
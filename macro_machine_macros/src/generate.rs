@@ -0,0 +1,2145 @@
+// Generation of machine module. Generated items are the same for machine with a single region and for each region
+// of machine with orthogonal regions, only Machine itself differs.
+
+use proc_macro2::{Span, TokenStream};
+use quote::{quote_spanned, ToTokens};
+use syn::punctuated::Punctuated;
+use syn::{Attribute, Ident, LitStr, Path, Token};
+
+use crate::parse::{Body, Context, Init, Machine, Node, Region, Target};
+
+// Generated code has hygiene of macro_rules!: its local variables are not visible from user-defined code,
+// but its items are.
+macro_rules! code {
+    ($($tokens:tt)*) => (quote_spanned!(Span::mixed_site()=> $($tokens)*));
+}
+
+pub fn machine(machine: &Machine) -> TokenStream {
+    match machine.body {
+        Body::Single(ref region) => single(machine, region),
+        Body::Regions{ref join, ref regions} => regions_machine(machine, join.as_ref(), regions),
+    }
+}
+
+fn local(name: &str) -> Ident {
+    Ident::new(name, Span::mixed_site())
+}
+
+fn name_of(ident: &Ident) -> LitStr {
+    LitStr::new(&ident.to_string(), ident.span())
+}
+
+// Name of machine-scoped context in user-defined code.
+fn context_name(context: Option<&Context>) -> Ident {
+    context.map(|context| context.name.clone()).unwrap_or_else(|| local("global_context"))
+}
+
+// Identifier which is pointed to in errors, but belongs to generated code. Like code built by macro_rules!,
+// code around it is not checked by lints.
+fn expanded(ident: &Ident) -> Ident {
+    Ident::new(&ident.to_string(), ident.span().resolved_at(Span::mixed_site()))
+}
+
+// Binding of `raise` in user-defined block. It queues Command which is executed after the current one is completed.
+// Unlike other locals of generated code, it's visible in the block.
+fn raise(block: &syn::Block) -> TokenStream {
+    let raise = Ident::new("raise", block.brace_token.span.join());
+    code!(let mut #raise = |command: Commands| raised.push_back(command);)
+}
+
+// State with values of its context fields.
+fn init(prefix: TokenStream, init: &Init) -> TokenStream {
+    let state = expanded(&init.state);
+    let names = init.fields.iter().map(|field| expanded(&field.name));
+    let values = init.fields.iter().map(|field| &field.value);
+    code!(#prefix #state{#(#names: #values),*})
+}
+
+// True if forwarded attributes derive the trait. Generated implementation of it is skipped then, otherwise they
+// would conflict.
+fn derives(attrs: &[Attribute], name: &str) -> bool {
+    attrs.iter().filter(|attr| attr.path().is_ident("derive")).any(|attr| {
+        attr.parse_args_with(Punctuated::<Path, Token![,]>::parse_terminated)
+            .map(|paths| paths.iter().any(|path| path.segments.last().is_some_and(|segment| segment.ident == name)))
+            .unwrap_or(false)
+    })
+}
+
+// Derives of generated traits which forwarded attributes don't derive.
+fn derive(attrs: &[Attribute], names: &[&str]) -> TokenStream {
+    names.iter().filter(|name| !derives(attrs, name)).map(|name| {
+        let name = Ident::new(name, Span::mixed_site());
+        code!(#[derive(#name)])
+    }).collect()
+}
+
+// Derives of items which are (de)serialized with `serde` feature. Serde is found by import of its re-export.
+// Like Clone, Serialize and Deserialize are implemented only if types of all fields implement them. Fields are
+// deserialized from owned data only.
+fn serde<T: ToTokens>(types: &[T]) -> TokenStream {
+    if !cfg!(feature = "serde") {
+        return TokenStream::new();
+    }
+    let bound = |bound: &str| {
+        let bounds: Vec<String> = types.iter().map(|ty| format!("for<'a> {}: __serde::{}", ty.to_token_stream(), bound)).collect();
+        LitStr::new(&bounds.join(", "), Span::mixed_site())
+    };
+    let serialize = bound("Serialize");
+    let deserialize = bound("de::DeserializeOwned");
+    code!(
+        #[derive(__serde::Serialize)]
+        #[derive(__serde::Deserialize)]
+        #[serde(crate = "self::__serde")]
+        #[serde(bound(serialize = #serialize, deserialize = #deserialize))]
+    )
+}
+
+// Field which is not (de)serialized and is default after deserialization.
+fn serde_skip() -> TokenStream {
+    if cfg!(feature = "serde") {
+        code!(#[serde(skip)])
+    } else {
+        TokenStream::new()
+    }
+}
+
+// Imports of macro_machine re-exports used by generated code with enabled features.
+fn imports(krate: &TokenStream) -> TokenStream {
+    let mut imports = TokenStream::new();
+    if cfg!(feature = "serde") {
+        imports.extend(code!(use #krate::__serde;));
+    }
+    if cfg!(feature = "log") {
+        imports.extend(code!(use #krate::__log;));
+    }
+    if cfg!(feature = "tracing") {
+        imports.extend(code!(use #krate::__tracing;));
+    }
+    imports
+}
+
+// Event of `log` and `tracing` features with the same level. Nothing is emitted without them.
+fn event(level: &str, log: TokenStream, tracing: TokenStream) -> TokenStream {
+    let level = Ident::new(level, Span::call_site());
+    let mut event = TokenStream::new();
+    if cfg!(feature = "log") {
+        event.extend(code!(__log::#level!(#log);));
+    }
+    if cfg!(feature = "tracing") {
+        event.extend(code!(__tracing::#level!(#tracing);));
+    }
+    event
+}
+
+// Keyword of functions which execute user-defined callbacks and `.await` of their calls. Both are empty
+// in synchronous machine, so the same code is generated for both kinds of machine.
+fn asyncness(asynchronous: bool) -> (TokenStream, TokenStream) {
+    if asynchronous {
+        (code!(async), code!(.await))
+    } else {
+        (TokenStream::new(), TokenStream::new())
+    }
+}
+
+// Observers and clock of async machine are Send, so its futures can be run by multithreaded executors.
+fn send(asynchronous: bool) -> TokenStream {
+    if asynchronous {
+        code!(+ ::std::marker::Send)
+    } else {
+        TokenStream::new()
+    }
+}
+
+// Body of Machine::execute in span of `tracing` feature. Span of async machine is entered on each poll
+// of execution, so it's not kept entered while machine awaits.
+fn execute_span(machine: &LitStr, asynchronous: bool, body: TokenStream) -> TokenStream {
+    if !cfg!(feature = "tracing") {
+        return body;
+    }
+    let span = code!(__tracing::debug_span!("execute", machine = #machine, command = ?cmd));
+    if asynchronous {
+        code!(__tracing::Instrument::instrument(async { #body }, #span).await)
+    } else {
+        code!(
+            let _span = #span.entered();
+            #body
+        )
+    }
+}
+
+// Commands and machine-scoped context shared by all regions of machine.
+fn common(machine: &Machine) -> TokenStream {
+    let commands_attrs = &machine.commands_attrs;
+    let names: Vec<&Ident> = machine.commands.iter().map(|command| &command.name).collect();
+    let strings = names.iter().map(|name| name_of(name));
+    let variants = machine.commands.iter().map(|command| {
+        let attrs = &command.attrs;
+        let name = &command.name;
+        match command.fields {
+            Some(ref fields) => {
+                let field_attrs = fields.iter().map(|field| &field.attrs);
+                let field_names = fields.iter().map(|field| &field.name);
+                let types = fields.iter().map(|field| &field.ty);
+                code!(#(#attrs)* #name{#(#(#field_attrs)* #field_names: #types),*})
+            },
+            None => code!(#(#attrs)* #name),
+        }
+    });
+    let payload: Vec<_> = machine.commands.iter().flat_map(|command| command.fields.iter().flatten()).map(|field| &field.ty).collect();
+    let commands_serde = serde(&payload);
+    let context_attrs = machine.context.as_ref().map(|context| &context.attrs[..]).unwrap_or(&[]);
+    let fields = machine.context.as_ref().map(|context| &context.fields[..]).unwrap_or(&[]);
+    let field_attrs = fields.iter().map(|field| &field.attrs);
+    let field_names: Vec<&Ident> = fields.iter().map(|field| &field.name).collect();
+    let types: Vec<_> = fields.iter().map(|field| &field.ty).collect();
+    let context_serde = serde(&types);
+    let queue_limit = match machine.queue {
+        Some(ref limit) => limit.to_token_stream(),
+        None => code!(32),
+    };
+    let pending_limit = match machine.pending {
+        Some(ref limit) => limit.to_token_stream(),
+        None => code!(32),
+    };
+    let commands_derive = derive(commands_attrs, &["Debug", "PartialEq"]);
+    let context_clone = if derives(context_attrs, "Clone") {
+        TokenStream::new()
+    } else {
+        code!(
+            impl ::std::clone::Clone for MachineContext where #(for<'a> #types: ::std::clone::Clone),* {
+                fn clone(&self) -> MachineContext {
+                    MachineContext{#(#field_names: self.#field_names.clone()),*}
+                }
+            }
+        )
+    };
+
+    code!(
+        #(#commands_attrs)*
+        #commands_serde
+        #commands_derive
+        pub enum Commands {
+            #(#variants),*
+        }
+
+        /// Identifier of Command without its payload.
+        #[derive(Debug)]
+        #[derive(PartialEq)]
+        #[derive(Eq)]
+        #[derive(PartialOrd)]
+        #[derive(Ord)]
+        #[derive(Hash)]
+        #[derive(Copy)]
+        #[derive(Clone)]
+        pub enum CommandId {
+            #(#names),*
+        }
+
+        /// All Commands in order of declaration.
+        pub const COMMANDS: &[CommandId] = &[#(CommandId::#names),*];
+
+        impl Commands {
+            pub fn id(&self) -> CommandId {
+                match *self {
+                    #(Commands::#names{..} => CommandId::#names),*
+                }
+            }
+        }
+
+        impl ::std::fmt::Display for CommandId {
+            fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+                match *self {
+                    #(CommandId::#names => f.write_str(#strings)),*
+                }
+            }
+        }
+
+        #(#context_attrs)*
+        #context_serde
+        pub struct MachineContext {#(#(#field_attrs)* pub #field_names: #types),*}
+
+        #context_clone
+
+        // Commands callbacks can raise during execution of one Command. Commands raising each other forever
+        // are stopped by it.
+        const QUEUE_LIMIT: usize = #queue_limit;
+        // Deferred Commands machine keeps until it reaches a state which reacts on them.
+        const PENDING_LIMIT: usize = #pending_limit;
+    )
+}
+
+// This structs keep user-defined contexts for states.
+// Debug, Clone and PartialEq are implemented only if all fields implement them, so context can keep values
+// of any type. Bounds are higher-ranked because bounds on concrete types are not allowed otherwise.
+// Traits derived by attributes of State Node are not implemented.
+fn params(node: &Node) -> TokenStream {
+    let attrs = &node.attrs;
+    let state = &node.state;
+    let types: Vec<_> = node.fields.iter().map(|field| &field.ty).collect();
+    let serde = serde(&types);
+    if node.fields.is_empty() {
+        let derive = derive(attrs, &["Debug", "PartialEq", "Clone"]);
+        return code!(
+            #(#attrs)*
+            #serde
+            #derive
+            pub struct #state {}
+        );
+    }
+    let field_attrs = node.fields.iter().map(|field| &field.attrs);
+    let names: Vec<&Ident> = node.fields.iter().map(|field| &field.name).collect();
+    let debug = if derives(attrs, "Debug") {
+        TokenStream::new()
+    } else {
+        let string = name_of(state);
+        let strings = names.iter().map(|name| name_of(name));
+        code!(
+            impl ::std::fmt::Debug for #state where #(for<'a> #types: ::std::fmt::Debug),* {
+                fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+                    f.debug_struct(#string)#(.field(#strings, &self.#names))*.finish()
+                }
+            }
+        )
+    };
+    let clone = if derives(attrs, "Clone") {
+        TokenStream::new()
+    } else {
+        code!(
+            impl ::std::clone::Clone for #state where #(for<'a> #types: ::std::clone::Clone),* {
+                fn clone(&self) -> #state {
+                    #state{#(#names: self.#names.clone()),*}
+                }
+            }
+        )
+    };
+    // Compares fields like derived PartialEq, so deriving Hash on the context is consistent with it.
+    let partial_eq = if derives(attrs, "PartialEq") {
+        TokenStream::new()
+    } else {
+        code!(
+            impl ::std::cmp::PartialEq for #state where #(for<'a> #types: ::std::cmp::PartialEq),* {
+                fn eq(&self, other: &#state) -> bool {
+                    #(self.#names == other.#names &&)* true
+                }
+            }
+        )
+    };
+    code!(
+        #(#attrs)*
+        #serde
+        pub struct #state {#(#(#field_attrs)* pub #names: #types),*}
+
+        #debug
+        #clone
+        #partial_eq
+    )
+}
+
+// Left state is kept in history only if history depth is declared, because it requires state context to be Clone.
+fn remember(node: &Node, history: bool) -> TokenStream {
+    let state = &node.state;
+    let remembered = if history {
+        code!(Some(States::#state{context: self.clone()}))
+    } else {
+        code!(None)
+    };
+    code!(
+        impl __Remember for #state {
+            fn remember(&self) -> ::std::option::Option<States> {
+                #remembered
+            }
+        }
+    )
+}
+
+// Enter/Leave processors with and without user-defined code.
+fn callback(node: &Node, name: Ident, block: Option<&syn::Block>, global_context: &Ident, asynchronous: bool) -> TokenStream {
+    let (asyncness, _) = asyncness(asynchronous);
+    let body = match block {
+        Some(block) => {
+            let binding = node.binding.as_ref().map(|binding| code!(let #binding = self;));
+            let raise = raise(block);
+            code!(#raise #binding #block;)
+        },
+        None => TokenStream::new(),
+    };
+    // User-defined code which always returns an error makes the final Ok(()) unreachable.
+    code!(
+        #[allow(unreachable_code)]
+        #asyncness fn #name(&mut self, #global_context: &mut MachineContext, raised: &mut ::std::collections::VecDeque<Commands>) -> ::std::result::Result<(), UserError> {
+            #body
+            Ok(())
+        }
+    )
+}
+
+// Reactions are tried in order of declaration, so the first one with passing guard wins.
+// If Command has reactions in this state but all their guards fail, it is reported as NoGuard. If all of them
+// move machine back and history is empty, it is reported as NoHistory.
+// State stays in the region while callbacks run and they change it through a reference. It's taken out only
+// when the next state is created, so context can be moved into it.
+// Back is the state machine can return to, if there is one.
+fn node(node: &Node, region: &Region, global_context: &Ident, asynchronous: bool) -> TokenStream {
+    let (asyncness, _) = asyncness(asynchronous);
+    let state = &node.state;
+    let binding = node.binding.clone().unwrap_or_else(|| local("context"));
+    let children = children(region, state);
+    let parent = !children.is_empty();
+    // If reactions are inherited by child state, context is a fresh instance of this parent state and source
+    // is the child state machine is in.
+    let context = if parent {
+        code!(
+            let mut fresh = #state{};
+            let (mut #binding, mut source) = match *state {
+                Some(States::#state{ref mut context}) => (context, None),
+                Some(ref mut source) => (&mut fresh, Some(source)),
+                None => unreachable!()
+            };
+        )
+    } else {
+        code!(
+            let (mut #binding, mut source): (&mut #state, ::std::option::Option<&mut States>) = match *state {
+                Some(States::#state{ref mut context}) => (context, None),
+                _ => unreachable!()
+            };
+        )
+    };
+    let job = Job{state, binding: &binding, parent, fallible: region.error.is_some(), global_context, asynchronous};
+    let reactions = node.reactions.iter().map(|reaction| {
+        let command = &reaction.command;
+        let bindings = &reaction.bindings;
+        // Reaction which moves machine back has implicit guard: history must not be empty.
+        let guard = match (reaction.guard.as_ref(), &reaction.target) {
+            (Some(guard), &Target::Back(_)) => code!(if back.is_some() && (#guard)),
+            (None, &Target::Back(_)) => code!(if back.is_some()),
+            (Some(guard), _) => code!(if #guard),
+            (None, _) => TokenStream::new(),
+        };
+        let body = reaction_body(&job, reaction.callback.as_ref(), &reaction.target);
+        code!(Commands::#command{#(ref #bindings,)* ..} #guard => #body)
+    });
+    let rejects = node.reactions.iter().map(|reaction| {
+        let command = &reaction.command;
+        let backward = node.reactions.iter().filter(|other| other.command == *command).all(|other| matches!(other.target, Target::Back(_)));
+        let no_history = if backward {
+            code!(Commands::#command{..} if back.is_none() => __Reaction::Reject(Error::NoHistory{state: StateId::#state, command: cmd.id()}),)
+        } else {
+            TokenStream::new()
+        };
+        code!(#no_history Commands::#command{..} => __Reaction::Reject(Error::NoGuard{state: StateId::#state, command: cmd.id()}),)
+    });
+    let enter = callback(node, local("enter"), node.enter.as_ref(), global_context, asynchronous);
+    let leave = callback(node, local("leave"), node.leave.as_ref(), global_context, asynchronous);
+    // Timeout moves machine like Command Reaction. State without timeout stays.
+    let timeout = match node.timeouts.first() {
+        Some(timeout) => reaction_body(&job, timeout.callback.as_ref(), &Target::State(timeout.target.clone())),
+        None => code!(__Reaction::Stay),
+    };
+
+    code!(
+        impl CanDoJob for #state {
+            #asyncness fn do_job(state: &mut ::std::option::Option<States>, cmd: &Commands, #global_context: &mut MachineContext, raised: &mut ::std::collections::VecDeque<Commands>, back: ::std::option::Option<StateId>) -> __Reaction {
+                #context
+                #[allow(unreachable_patterns)]
+                match *cmd {
+                    #(#reactions)*
+                    _ => match *cmd {
+                        #(#rejects)*
+                        _ => __Reaction::Reject(Error::Unhandled{state: StateId::#state, command: cmd.id()})
+                    }
+                }
+            }
+            #enter
+            #leave
+            #asyncness fn timeout(state: &mut ::std::option::Option<States>, #global_context: &mut MachineContext, raised: &mut ::std::collections::VecDeque<Commands>) -> __Reaction {
+                #context
+                #timeout
+            }
+        }
+    )
+}
+
+// States which are within this parent state, so machine can be in them when reactions of the parent are executed.
+fn children<'a>(region: &'a Region, state: &Ident) -> Vec<&'a Ident> {
+    region.states.iter().filter(|child| {
+        let mut parent = child.parent.as_ref();
+        while let Some(current) = parent {
+            if current == state {
+                return true;
+            }
+            parent = region.states.iter().find(|declared| declared.name == *current).and_then(|declared| declared.parent.as_ref());
+        }
+        false
+    }).map(|child| &child.name).collect()
+}
+
+// State whose Command Reactions and timeout are generated. Callbacks of the state can fail if error is declared.
+struct Job<'a> {
+    state: &'a Ident,
+    binding: &'a Ident,
+    parent: bool,
+    fallible: bool,
+    global_context: &'a Ident,
+    asynchronous: bool,
+}
+
+// If Leave callback fails, machine stays in the state. Contexts are kept as callbacks changed them, they are
+// not copied, so they don't have to be Clone. Commands raised by the reaction are dropped.
+fn reaction_body(job: &Job, callback: Option<&syn::Block>, target: &Target) -> TokenStream {
+    let Job{state, binding, parent, fallible, global_context, asynchronous} = *job;
+    let (_, awaited) = asyncness(asynchronous);
+    let (raised_len, rollback) = match *target {
+        Target::Stay => (TokenStream::new(), TokenStream::new()),
+        _ if fallible => (code!(let raised_len = raised.len();), code!(raised.truncate(raised_len);)),
+        _ => (TokenStream::new(), TokenStream::new()),
+    };
+    // Leave current state and its parents which don't contain the next state.
+    let leave = |to: TokenStream| code!(
+        let from = match source {
+            Some(ref source) => source.id(),
+            None => StateId::#state
+        };
+        let left = match source {
+            Some(ref mut source) => source.leave(#global_context, raised) #awaited,
+            None => #binding.leave(#global_context, raised) #awaited .map_err(|error| Error::Leave{state: StateId::#state, error})
+        };
+        let left = match left {
+            Ok(()) => leave_parents(from, #to, #global_context, raised) #awaited,
+            Err(error) => Err(error)
+        };
+        if let Err(error) = left {
+            #rollback
+            return __Reaction::Reject(error);
+        }
+    );
+    let result = match *target {
+        // Machine stays, nothing to do.
+        Target::Stay => code!(__Reaction::Stay),
+        // Previous state is restored by machine. Reaction is tried only if history is not empty.
+        Target::Back(_) => {
+            let leave = leave(code!(to));
+            code!(
+                let to = back.expect("history is empty");
+                #leave
+                __Reaction::Back
+            )
+        },
+        // Context is taken out of the region and moved into the new state. Left state is kept in history
+        // if machine has one.
+        Target::State(ref next) => {
+            let new_state = &next.state;
+            let leave = leave(code!(StateId::#new_state));
+            let next = init(TokenStream::new(), next);
+            let fresh = if parent {
+                code!(fresh)
+            } else {
+                code!(unreachable!())
+            };
+            code!(
+                #leave
+                let previous = match source {
+                    Some(ref source) => source.remember(),
+                    None => #binding.remember()
+                };
+                let #binding = match state.take() {
+                    Some(States::#state{context}) => context,
+                    _ => #fresh
+                };
+                __Reaction::Change(States::#new_state{context: #next}, previous)
+            )
+        },
+    };
+    let callback = callback.map(|callback| {
+        let raise = raise(callback);
+        code!(#raise #callback;)
+    });
+    code!({
+        #raised_len
+        #callback
+        #result
+    })
+}
+
+// States, their reactions and transitions. Machine keeps a single region, machine with orthogonal
+// regions keeps one per region. Commands and machine-scoped context are declared by the caller.
+// Machine is a name of machine and its region if machine has regions. It's given to events of `log` and `tracing`.
+fn core(region: &Region, vis: TokenStream, context: Option<&Context>, machine: &LitStr, asynchronous: bool) -> TokenStream {
+    let (asyncness, awaited) = asyncness(asynchronous);
+    let send = send(asynchronous);
+    let global_context = context_name(context);
+    let states: Vec<&Ident> = region.states.iter().map(|state| &state.name).collect();
+    let strings: Vec<LitStr> = states.iter().map(|state| name_of(state)).collect();
+    let parent_of = region.states.iter().map(|state| match state.parent {
+        Some(ref parent) => code!(Some(StateId::#parent)),
+        None => code!(None),
+    });
+    let mut parents: Vec<&Ident> = Vec::new();
+    for parent in region.states.iter().filter_map(|state| state.parent.as_ref()) {
+        if !parents.contains(&parent) {
+            parents.push(parent);
+        }
+    }
+    let parents = &parents;
+    let nodes: Vec<&Ident> = region.nodes.iter().map(|node| &node.state).collect();
+    let params = region.nodes.iter().map(params);
+    let remember = region.nodes.iter().map(|node| remember(node, region.history.is_some()));
+    let history_depth = match region.history {
+        Some(ref depth) => depth.to_token_stream(),
+        None => code!(0),
+    };
+    let jobs = region.nodes.iter().map(|node| self::node(node, region, &global_context, asynchronous));
+    let user_error = match region.error {
+        Some(ref error) => error.ty.to_token_stream(),
+        None => code!(::std::convert::Infallible),
+    };
+    // Switch machine to the error state when Enter callback failed.
+    let fail_state = region.error.as_ref().map(|error| {
+        let state = &error.state.state;
+        let context = init(TokenStream::new(), &error.state);
+        code!(
+            if result.is_err() {
+                self.state = Some(States::#state{context: #context});
+            }
+        )
+    });
+    let initial = &region.initial.state;
+    let states_serde = serde(&states);
+    let region_serde = serde(&[code!(States)]);
+    let dispatched = event("trace",
+        code!("{}: {:?} dispatched in {}", #machine, cmd, from),
+        code!(machine = #machine, state = %from, command = ?cmd, "dispatch"));
+    let transition = event("debug",
+        code!("{}: {} --{:?}--> {}", #machine, from, cmd, to),
+        code!(machine = #machine, from = %from, command = ?cmd, to = %to, "transition"));
+    let timed_out = event("debug",
+        code!("{}: {} --timeout--> {}", #machine, from, to),
+        code!(machine = #machine, from = %from, to = %to, "timeout"));
+    let rejected = event("debug",
+        code!("{}: {:?} rejected in {}: {}", #machine, cmd, from, error),
+        code!(machine = #machine, state = %from, command = ?cmd, error = %error, "rejected"));
+    let serde_skip = serde_skip();
+    let error_state = region.error.as_ref().map(|error| &error.state.state).into_iter();
+    let timeouts = region.nodes.iter().flat_map(|node| node.timeouts.iter().map(move |timeout| {
+        let state = &node.state;
+        let to = &timeout.target.state;
+        let millis = timeout.millis;
+        code!(
+            Timeout{
+                from: StateId::#state,
+                after: ::std::time::Duration::from_millis(#millis),
+                to: StateId::#to
+            },
+        )
+    }));
+    let deferrals = region.nodes.iter().flat_map(|node| node.defers.iter().map(move |command| {
+        let state = &node.state;
+        code!(
+            Deferral{
+                state: StateId::#state,
+                command: CommandId::#command
+            },
+        )
+    }));
+    let deferred = event("debug",
+        code!("{}: {:?} deferred in {}", #machine, cmd, from),
+        code!(machine = #machine, state = %from, command = ?cmd, "deferred"));
+    let timed: Vec<&Ident> = region.nodes.iter().filter(|node| !node.timeouts.is_empty()).map(|node| &node.state).collect();
+    let enter = region.nodes.iter().map(|node| node.enter.is_some());
+    let leave = region.nodes.iter().map(|node| node.leave.is_some());
+    let transitions = region.nodes.iter().flat_map(|node| node.reactions.iter().map(move |reaction| {
+        let state = &node.state;
+        let command = &reaction.command;
+        let guard = match reaction.guard {
+            Some(ref guard) => code!(Some(::std::stringify!(#guard))),
+            None => code!(None),
+        };
+        let to = match reaction.target {
+            Target::Stay => code!(Target::Stay),
+            Target::State(ref next) => {
+                let next = &next.state;
+                code!(Target::State(StateId::#next))
+            },
+            Target::Back(_) => code!(Target::Back),
+        };
+        code!(
+            Transition{
+                from: StateId::#state,
+                command: CommandId::#command,
+                guard: #guard,
+                to: #to
+            },
+        )
+    }));
+
+    code!(
+        // Result of Command Reaction. Changed state is given with the left state to keep in history.
+        // Machine stays in its state otherwise.
+        enum __Reaction {
+            Stay,
+            Change(States, ::std::option::Option<States>),
+            Back,
+            Reject(Error)
+        }
+        // Reactions and timeouts are given the state machine is in. It's this state or one of its child states.
+        trait CanDoJob {
+            #asyncness fn do_job(state: &mut ::std::option::Option<States>, cmd: &Commands, global_context: &mut MachineContext, raised: &mut ::std::collections::VecDeque<Commands>, back: ::std::option::Option<StateId>) -> __Reaction;
+            #asyncness fn leave(&mut self, global_context: &mut MachineContext, raised: &mut ::std::collections::VecDeque<Commands>) -> ::std::result::Result<(), UserError>;
+            #asyncness fn enter(&mut self, global_context: &mut MachineContext, raised: &mut ::std::collections::VecDeque<Commands>) -> ::std::result::Result<(), UserError>;
+            #asyncness fn timeout(state: &mut ::std::option::Option<States>, global_context: &mut MachineContext, raised: &mut ::std::collections::VecDeque<Commands>) -> __Reaction;
+        }
+        trait __Remember {
+            fn remember(&self) -> ::std::option::Option<States>;
+        }
+
+        #(#params)*
+
+        #(#remember)*
+
+        const HISTORY_DEPTH: usize = #history_depth;
+
+        #(#jobs)*
+
+        #states_serde
+        pub enum States {
+            #(#states {context: #states}),*
+        }
+
+        // Formats like derived Debug, but only if contexts implement it.
+        impl ::std::fmt::Debug for States where #(for<'a> #states: ::std::fmt::Debug),* {
+            fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+                match *self {
+                    #(States::#states{ref context} => f.debug_struct(#strings).field("context", context).finish()),*
+                }
+            }
+        }
+
+        impl ::std::clone::Clone for States where #(for<'a> #states: ::std::clone::Clone),* {
+            fn clone(&self) -> States {
+                match *self {
+                    #(States::#states{ref context} => States::#states{context: context.clone()}),*
+                }
+            }
+        }
+
+        impl ::std::cmp::PartialEq for States where #(for<'a> #states: ::std::cmp::PartialEq),* {
+            fn eq(&self, other: &States) -> bool {
+                #[allow(unreachable_patterns)]
+                match (self, other) {
+                    #((&States::#states{context: ref a}, &States::#states{context: ref b}) => a == b,)*
+                    _ => false
+                }
+            }
+        }
+
+        impl States {
+            pub fn id(&self) -> StateId {
+                match *self {
+                    #(States::#states{..} => StateId::#states),*
+                }
+            }
+            #asyncness fn leave(&mut self, global_context: &mut MachineContext, raised: &mut ::std::collections::VecDeque<Commands>) -> ::std::result::Result<(), Error> {
+                match *self {
+                    #(States::#nodes{ ref mut context } => context.leave(global_context, raised) #awaited .map_err(|error| Error::Leave{state: StateId::#nodes, error})),*
+                }
+            }
+            fn remember(&self) -> ::std::option::Option<States> {
+                match *self {
+                    #(States::#nodes{ ref context } => context.remember()),*
+                }
+            }
+        }
+
+        // Parent states have no context, so their callbacks and reactions are executed on a fresh instance.
+        #asyncness fn parent_job(id: StateId, cmd: &Commands, global_context: &mut MachineContext, raised: &mut ::std::collections::VecDeque<Commands>, state: &mut ::std::option::Option<States>, back: ::std::option::Option<StateId>) -> __Reaction {
+            #(if id == StateId::#parents {
+                return <#parents as CanDoJob>::do_job(state, cmd, global_context, raised, back) #awaited;
+            })*
+            unreachable!()
+        }
+        // Timeout of the state or of its parent which expired first.
+        #asyncness fn timeout_job(id: StateId, global_context: &mut MachineContext, raised: &mut ::std::collections::VecDeque<Commands>, state: &mut ::std::option::Option<States>) -> __Reaction {
+            #(if id == StateId::#timed {
+                return <#timed as CanDoJob>::timeout(state, global_context, raised) #awaited;
+            })*
+            __Reaction::Stay
+        }
+        #asyncness fn enter_parent(id: StateId, global_context: &mut MachineContext, raised: &mut ::std::collections::VecDeque<Commands>) -> ::std::result::Result<(), UserError> {
+            #(if id == StateId::#parents {
+                return #parents{}.enter(global_context, raised) #awaited;
+            })*
+            unreachable!()
+        }
+        #asyncness fn leave_parent(id: StateId, global_context: &mut MachineContext, raised: &mut ::std::collections::VecDeque<Commands>) -> ::std::result::Result<(), UserError> {
+            #(if id == StateId::#parents {
+                return #parents{}.leave(global_context, raised) #awaited;
+            })*
+            unreachable!()
+        }
+
+        // Leave parents of the state from the bottom up to the parent which contains the new state.
+        #asyncness fn leave_parents(from: StateId, to: StateId, global_context: &mut MachineContext, raised: &mut ::std::collections::VecDeque<Commands>) -> ::std::result::Result<(), Error> {
+            let mut parent = from.parent();
+            while let Some(state) = parent {
+                if state != to && to.is_within(state) {
+                    break;
+                }
+                leave_parent(state, global_context, raised) #awaited .map_err(|error| Error::Leave{state, error})?;
+                parent = state.parent();
+            }
+            Ok(())
+        }
+
+        // Enter parents of the new state which don't contain the old one from the top down.
+        #asyncness fn enter_parents(from: ::std::option::Option<StateId>, to: StateId, global_context: &mut MachineContext, raised: &mut ::std::collections::VecDeque<Commands>) -> ::std::result::Result<(), Error> {
+            let mut parents = entered_states(from, to);
+            parents.pop();
+            for state in parents {
+                enter_parent(state, global_context, raised) #awaited .map_err(|error| Error::Enter{state, error})?;
+            }
+            Ok(())
+        }
+
+        /// Identifier of State without its context.
+        #[derive(Debug)]
+        #[derive(PartialEq)]
+        #[derive(Eq)]
+        #[derive(PartialOrd)]
+        #[derive(Ord)]
+        #[derive(Hash)]
+        #[derive(Copy)]
+        #[derive(Clone)]
+        pub enum StateId {
+            #(#states),*
+        }
+
+        impl StateId {
+            pub fn parent(self) -> ::std::option::Option<StateId> {
+                match self {
+                    #(StateId::#states => #parent_of),*
+                }
+            }
+            // True if this is the state or one of its child states.
+            pub fn is_within(self, state: StateId) -> bool {
+                let mut current = Some(self);
+                while let Some(id) = current {
+                    if id == state {
+                        return true;
+                    }
+                    current = id.parent();
+                }
+                false
+            }
+            // Timeout declared in State Node of this state. Timeouts of parents are not included.
+            pub fn timeout(self) -> ::std::option::Option<::std::time::Duration> {
+                TIMEOUTS.iter().find(|timeout| timeout.from == self).map(|timeout| timeout.after)
+            }
+            // Commands deferred in this state or its parents.
+            pub fn deferred_commands(self) -> ::std::vec::Vec<CommandId> {
+                COMMANDS.iter().cloned().filter(|command| self.is_deferred(*command)).collect()
+            }
+            pub fn is_deferred(self, command: CommandId) -> bool {
+                DEFERRALS.iter().any(|deferral| deferral.command == command && self.is_within(deferral.state))
+            }
+            // Commands which have reactions in this state or its parents. Guards are not checked.
+            pub fn available_commands(self) -> ::std::vec::Vec<CommandId> {
+                COMMANDS.iter().cloned().filter(|command| {
+                    TRANSITIONS.iter().any(|transition| transition.command == *command && self.is_within(transition.from))
+                }).collect()
+            }
+        }
+
+        impl ::std::fmt::Display for StateId {
+            fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+                match *self {
+                    #(StateId::#states => f.write_str(#strings)),*
+                }
+            }
+        }
+
+        /// Error which Enter/Leave callbacks can return.
+        pub type UserError = #user_error;
+
+        /// Error returned by `Machine::execute`.
+        #[derive(Debug)]
+        pub enum Error {
+            /// Command has no reactions in this state.
+            Unhandled{state: StateId, command: CommandId},
+            /// Command has reactions in this state, but none of their guards passed.
+            NoGuard{state: StateId, command: CommandId},
+            /// All reactions on Command in this state move machine back, but history is empty.
+            NoHistory{state: StateId, command: CommandId},
+            /// Enter callback of the state failed. Machine was switched to the error state.
+            Enter{state: StateId, error: UserError},
+            /// Leave callback of the state failed. Machine stayed in this state.
+            Leave{state: StateId, error: UserError},
+            /// Callbacks raised more Commands during execution of one Command than queue allows. Command is dropped
+            /// with the rest of the queue.
+            Overflow{command: CommandId},
+            /// Command is deferred, but pending buffer is full. Command is dropped.
+            PendingFull{command: CommandId}
+        }
+
+        impl ::std::fmt::Display for Error {
+            fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+                match *self {
+                    Error::Unhandled{state, command} => write!(f, "command {} is not handled in state {}", command, state),
+                    Error::NoGuard{state, command} => write!(f, "no guard passed for command {} in state {}", command, state),
+                    Error::NoHistory{state, command} => write!(f, "no state in history to return to on command {} in state {}", command, state),
+                    Error::Enter{state, ref error} => write!(f, "failed to enter state {}: {:?}", state, error),
+                    Error::Leave{state, ref error} => write!(f, "failed to leave state {}: {:?}", state, error),
+                    Error::Overflow{command} => write!(f, "queue of raised commands overflowed by command {}", command),
+                    Error::PendingFull{command} => write!(f, "pending buffer is full, command {} is dropped", command)
+                }
+            }
+        }
+
+        impl ::std::error::Error for Error {
+            fn source(&self) -> ::std::option::Option<&(dyn ::std::error::Error + 'static)> {
+                // UserError is the source only if it implements Error. Method of Source itself is found before the
+                // method of its reference, which is implemented for any UserError.
+                struct Source<'a, T>(&'a T);
+                trait ErrorSource<'a> {
+                    fn get(&self) -> ::std::option::Option<&'a (dyn ::std::error::Error + 'static)>;
+                }
+                impl<'a, T: ::std::error::Error + 'static> ErrorSource<'a> for Source<'a, T> {
+                    fn get(&self) -> ::std::option::Option<&'a (dyn ::std::error::Error + 'static)> {
+                        ::std::option::Option::Some(self.0)
+                    }
+                }
+                trait NoSource<'a> {
+                    fn get(&self) -> ::std::option::Option<&'a (dyn ::std::error::Error + 'static)>;
+                }
+                impl<'a, T> NoSource<'a> for &Source<'a, T> {
+                    fn get(&self) -> ::std::option::Option<&'a (dyn ::std::error::Error + 'static)> {
+                        ::std::option::Option::None
+                    }
+                }
+                match *self {
+                    Error::Enter{ref error, ..} | Error::Leave{ref error, ..} => (&Source(error)).get(),
+                    _ => ::std::option::Option::None
+                }
+            }
+        }
+
+        /// Where Command Reaction moves machine.
+        #[derive(Debug)]
+        #[derive(PartialEq)]
+        #[derive(Eq)]
+        #[derive(Copy)]
+        #[derive(Clone)]
+        pub enum Target {
+            /// Machine stays in the state. Reaction has no next state.
+            Stay,
+            /// Machine moves to the state.
+            State(StateId),
+            /// Machine moves back to the previous state from history.
+            Back
+        }
+
+        /// Command Reaction declared in State Node. Reactions inherited by child states are listed only for their parent.
+        #[derive(Debug)]
+        #[derive(PartialEq)]
+        #[derive(Eq)]
+        #[derive(Copy)]
+        #[derive(Clone)]
+        pub struct Transition {
+            pub from: StateId,
+            pub command: CommandId,
+            /// Source code of guard as given by `stringify!`.
+            pub guard: ::std::option::Option<&'static str>,
+            pub to: Target
+        }
+
+        /// Command deferred in State Node. It's deferred in child states of `state` too.
+        #[derive(Debug)]
+        #[derive(PartialEq)]
+        #[derive(Eq)]
+        #[derive(Copy)]
+        #[derive(Clone)]
+        pub struct Deferral {
+            pub state: StateId,
+            pub command: CommandId
+        }
+
+        /// Timeout declared in State Node. Machine moves to `to` if it stays in `from` or its child states for `after`.
+        #[derive(Debug)]
+        #[derive(PartialEq)]
+        #[derive(Eq)]
+        #[derive(Copy)]
+        #[derive(Clone)]
+        pub struct Timeout {
+            pub from: StateId,
+            pub after: ::std::time::Duration,
+            pub to: StateId
+        }
+
+        // States which have Enter/Leave callbacks.
+        struct __Node {
+            state: StateId,
+            enter: bool,
+            leave: bool
+        }
+        /// All States in order of declaration.
+        pub const STATES: &[StateId] = &[#(StateId::#states),*];
+        const NODES: &[__Node] = &[#(__Node{
+            state: StateId::#nodes,
+            enter: #enter,
+            leave: #leave
+        }),*];
+        /// All Command Reactions in order of declaration.
+        pub const TRANSITIONS: &[Transition] = &[
+            #(#transitions)*
+        ];
+        /// All timeouts in order of declaration.
+        pub const TIMEOUTS: &[Timeout] = &[
+            #(#timeouts)*
+        ];
+        /// All deferred Commands in order of declaration.
+        pub const DEFERRALS: &[Deferral] = &[
+            #(#deferrals)*
+        ];
+
+        // Writes states and transitions in DOT language. Nodes are prefixed, so regions can be drawn in one graph.
+        // Child states are drawn inside of clusters of their parents.
+        #vis fn write_dot(out: &mut ::std::string::String, prefix: &str, initial: StateId) {
+            fn write_state(out: &mut ::std::string::String, prefix: &str, state: StateId, indent: &str) {
+                let children: ::std::vec::Vec<StateId> = STATES.iter().cloned().filter(|child| child.parent() == Some(state)).collect();
+                if children.is_empty() {
+                    out.push_str(&format!("{}\"{}{}\" [label=\"{}\"];\n", indent, prefix, state, state));
+                    return;
+                }
+                out.push_str(&format!("{}subgraph \"cluster_{}{}\" {{\n", indent, prefix, state));
+                out.push_str(&format!("{}    label=\"{}\";\n", indent, state));
+                out.push_str(&format!("{}    \"{}{}\" [label=\"{}\"];\n", indent, prefix, state, state));
+                for child in children {
+                    write_state(out, prefix, child, &format!("{}    ", indent));
+                }
+                out.push_str(&format!("{}}}\n", indent));
+            }
+
+            out.push_str(&format!("    \"{}__start\" [shape=point];\n", prefix));
+            for state in STATES.iter().filter(|state| state.parent().is_none()) {
+                write_state(out, prefix, *state, "    ");
+            }
+            if TRANSITIONS.iter().any(|transition| match transition.to { Target::Back => true, _ => false }) {
+                out.push_str(&format!("    \"{}__history\" [shape=circle, label=\"H\"];\n", prefix));
+            }
+            out.push_str(&format!("    \"{}__start\" -> \"{}{}\";\n", prefix, prefix, initial));
+            for transition in TRANSITIONS {
+                let to = match transition.to {
+                    Target::Stay => transition.from.to_string(),
+                    Target::State(state) => state.to_string(),
+                    Target::Back => ::std::string::String::from("__history")
+                };
+                let label = match transition.guard {
+                    Some(guard) => format!("{} [{}]", transition.command, guard.replace('\\', "\\\\").replace('"', "\\\"")),
+                    None => transition.command.to_string()
+                };
+                out.push_str(&format!("    \"{}{}\" -> \"{}{}\" [label=\"{}\"];\n", prefix, transition.from, prefix, to, label));
+            }
+            for timeout in TIMEOUTS {
+                out.push_str(&format!("    \"{}{}\" -> \"{}{}\" [label=\"after {:?}\", style=dashed];\n", prefix, timeout.from, prefix, timeout.to, timeout.after));
+            }
+        }
+
+        /// Result of `Machine::analyze`.
+        #[derive(Debug)]
+        #[derive(PartialEq)]
+        #[derive(Eq)]
+        #[derive(Clone)]
+        pub struct Analysis {
+            /// States machine can't reach from the initial state or the error state.
+            pub unreachable: ::std::vec::Vec<StateId>,
+            /// Reachable states which machine can't leave.
+            pub deadlocks: ::std::vec::Vec<StateId>,
+            /// Commands which no state reacts to.
+            pub unused_commands: ::std::vec::Vec<CommandId>
+        }
+
+        impl Analysis {
+            pub fn is_ok(&self) -> bool {
+                self.unreachable.is_empty() && self.deadlocks.is_empty() && self.unused_commands.is_empty()
+            }
+        }
+
+        // Walks transitions and timeouts from the initial state and the error state. Parent state is reached when machine
+        // is in one of its child states, its reactions and timeouts are inherited by them. Guards are not checked.
+        #vis fn analyze() -> Analysis {
+            let mut current = vec![StateId::#initial #(, StateId::#error_state)*];
+            let mut i = 0;
+            while i < current.len() {
+                let state = current[i];
+                let reactions = TRANSITIONS.iter().filter(|transition| state.is_within(transition.from)).filter_map(|transition| match transition.to {
+                    Target::State(next) => Some(next),
+                    _ => None
+                });
+                let timeouts = TIMEOUTS.iter().filter(|timeout| state.is_within(timeout.from)).map(|timeout| timeout.to);
+                for next in reactions.chain(timeouts) {
+                    if !current.contains(&next) {
+                        current.push(next);
+                    }
+                }
+                i += 1;
+            }
+            let leaves = |state: StateId| TRANSITIONS.iter().any(|transition| state.is_within(transition.from) && match transition.to {
+                Target::State(next) => next != state,
+                Target::Back => true,
+                Target::Stay => false
+            }) || TIMEOUTS.iter().any(|timeout| state.is_within(timeout.from) && timeout.to != state);
+            Analysis{
+                unreachable: STATES.iter().cloned().filter(|state| !current.iter().any(|reached| reached.is_within(*state))).collect(),
+                deadlocks: STATES.iter().cloned().filter(|state| current.contains(state) && !leaves(*state)).collect(),
+                unused_commands: COMMANDS.iter().cloned().filter(|command| !TRANSITIONS.iter().any(|transition| transition.command == *command)).collect()
+            }
+        }
+
+        /// Observer of Commands and State changes. Methods do nothing by default, so only needed ones can be implemented.
+        pub trait Observer {
+            /// Command is dispatched to machine in the State.
+            fn on_dispatch(&mut self, state: StateId, command: &Commands) {}
+            /// Command is rejected in the State. Machine stays in it.
+            fn on_reject(&mut self, state: StateId, command: &Commands, error: &Error) {}
+            /// State is left. Child State is left before its parents.
+            fn on_leave(&mut self, state: StateId) {}
+            /// Machine moves from the State to the next one on Command.
+            fn on_transition(&mut self, from: StateId, command: &Commands, to: StateId) {}
+            /// State is entered. Parent State is entered before its children.
+            fn on_enter(&mut self, state: StateId) {}
+            /// Machine moves from the State to the next one because timeout expired.
+            fn on_timeout(&mut self, from: StateId, to: StateId) {}
+            /// Command is not handled, but deferred in the State. Machine keeps it until it reaches a state which
+            /// reacts on it.
+            fn on_defer(&mut self, state: StateId, command: &Commands) {}
+        }
+
+        // States left on transition: the state and its parents which don't contain the next state, from the bottom up.
+        fn left_states(from: StateId, to: StateId) -> ::std::vec::Vec<StateId> {
+            let mut left = vec![from];
+            let mut parent = from.parent();
+            while let Some(state) = parent {
+                if state != to && to.is_within(state) {
+                    break;
+                }
+                left.push(state);
+                parent = state.parent();
+            }
+            left
+        }
+
+        // States entered on transition: parents of the new state which don't contain the old one, from the top down,
+        // and the new state itself.
+        fn entered_states(from: ::std::option::Option<StateId>, to: StateId) -> ::std::vec::Vec<StateId> {
+            let mut entered = vec![to];
+            let mut parent = to.parent();
+            while let Some(state) = parent {
+                if let Some(from) = from {
+                    if state != from && from.is_within(state) {
+                        break;
+                    }
+                }
+                entered.insert(0, state);
+                parent = state.parent();
+            }
+            entered
+        }
+
+        // Current state of machine or its region and states it was in before. The last one is the most recent.
+        // Observers are notified about its changes.
+        #region_serde
+        #vis struct __Region {
+            state: ::std::option::Option<States>,
+            history: ::std::collections::VecDeque<States>,
+            #serde_skip
+            observers: ::std::vec::Vec<::std::boxed::Box<dyn Observer #send>>,
+            // Current state and its parents with time they were entered, from the top down. Restored region has
+            // no timers until it's polled.
+            #serde_skip
+            timers: ::std::vec::Vec<(StateId, ::std::time::Instant)>
+        }
+
+        impl __Region {
+            #vis fn new() -> __Region {
+                __Region{state: None, history: ::std::collections::VecDeque::new(), observers: ::std::vec::Vec::new(), timers: ::std::vec::Vec::new()}
+            }
+            // Region in the state without execution of its Enter callbacks.
+            #vis fn restore(state: States) -> __Region {
+                __Region{state: Some(state), history: ::std::collections::VecDeque::new(), observers: ::std::vec::Vec::new(), timers: ::std::vec::Vec::new()}
+            }
+            #vis fn add_observer(&mut self, observer: ::std::boxed::Box<dyn Observer #send>) {
+                self.observers.push(observer);
+            }
+            // Timers of current state and its parents are started from now.
+            #vis fn start_timers(&mut self, now: ::std::time::Instant) {
+                let state = self.state().id();
+                self.timers = entered_states(None, state).into_iter().map(|state| (state, now)).collect();
+            }
+            // Leave of the old state is reported after Leave callbacks succeeded.
+            fn notify_leave(&mut self, from: StateId, to: StateId) {
+                for observer in self.observers.iter_mut() {
+                    for state in left_states(from, to) {
+                        observer.on_leave(state);
+                    }
+                }
+            }
+            // Command which isn't handled is reported by machine, because other regions can handle it.
+            #vis fn notify_reject(&mut self, cmd: &Commands, error: &Error) {
+                let from = self.state().id();
+                #rejected
+                for observer in self.observers.iter_mut() {
+                    observer.on_reject(from, cmd, error);
+                }
+            }
+            // Command is reported as deferred after machine put it into pending buffer.
+            #vis fn notify_defer(&mut self, cmd: &Commands) {
+                let from = self.state().id();
+                #deferred
+                for observer in self.observers.iter_mut() {
+                    observer.on_defer(from, cmd);
+                }
+            }
+            fn notify_change(&mut self, from: StateId, cmd: &Commands, to: StateId) {
+                #transition
+                self.notify_leave(from, to);
+                for observer in self.observers.iter_mut() {
+                    observer.on_transition(from, cmd, to);
+                }
+            }
+            fn remember(&mut self, previous: ::std::option::Option<States>) {
+                if let Some(previous) = previous {
+                    self.history.push_back(previous);
+                    if self.history.len() > HISTORY_DEPTH {
+                        self.history.pop_front();
+                    }
+                }
+            }
+            // The earliest time timeout of current state or of its parents expires.
+            #vis fn next_deadline(&self) -> ::std::option::Option<::std::time::Instant> {
+                self.timers.iter().filter_map(|&(state, since)| state.timeout().map(|after| since + after)).min()
+            }
+            // Fires timeout which expired by now. If several timeouts expired, the one of the innermost state wins.
+            // Timeout of the new state is started from now, so it's fired by one of next polls.
+            #vis #asyncness fn poll(&mut self, now: ::std::time::Instant, global_context: &mut MachineContext, raised: &mut ::std::collections::VecDeque<Commands>) -> ::std::result::Result<bool, Error> {
+                if self.timers.is_empty() {
+                    self.start_timers(now);
+                }
+                let expired = self.timers.iter().rev().find(|&&(state, since)| match state.timeout() {
+                    Some(after) => since + after <= now,
+                    None => false
+                });
+                let owner = match expired {
+                    Some(&(owner, _)) => owner,
+                    None => return Ok(false)
+                };
+                let from = self.state().id();
+                match timeout_job(owner, global_context, raised, &mut self.state) #awaited {
+                    __Reaction::Change(state, previous) => {
+                        self.remember(previous);
+                        let to = state.id();
+                        #timed_out
+                        self.notify_leave(from, to);
+                        for observer in self.observers.iter_mut() {
+                            observer.on_timeout(from, to);
+                        }
+                        self.change_state(Some(from), state, global_context, raised, now) #awaited .map(|_| true)
+                    },
+                    __Reaction::Reject(error) => {
+                        Err(error)
+                    },
+                    __Reaction::Stay => Ok(false),
+                    __Reaction::Back => unreachable!()
+                }
+            }
+            #vis fn state(&self) -> &States {
+                self.state.as_ref().expect("machine is not in any state: its execution panicked")
+            }
+            #vis fn history(&self) -> &::std::collections::VecDeque<States> {
+                &self.history
+            }
+            #vis #asyncness fn execute(&mut self, cmd: &Commands, global_context: &mut MachineContext, raised: &mut ::std::collections::VecDeque<Commands>, now: ::std::time::Instant) -> ::std::result::Result<(), Error> {
+                let from = self.state().id();
+                #dispatched
+                for observer in self.observers.iter_mut() {
+                    observer.on_dispatch(from, cmd);
+                }
+                let back = self.history.back().map(States::id);
+                let mut reaction = match from {
+                    #(StateId::#nodes => <#nodes as CanDoJob>::do_job(&mut self.state, cmd, global_context, raised, back) #awaited),*
+                };
+                // Command rejected by the state is passed to its parents. Rejection by guard is reported over
+                // rejection by empty history, which is reported over missing reactions.
+                let mut no_guard = false;
+                let mut no_history = false;
+                let mut parent = from.parent();
+                while let Some(id) = parent {
+                    match reaction {
+                        __Reaction::Reject(Error::Unhandled{..}) => {},
+                        __Reaction::Reject(Error::NoGuard{..}) => no_guard = true,
+                        __Reaction::Reject(Error::NoHistory{..}) => no_history = true,
+                        _ => break
+                    }
+                    reaction = parent_job(id, cmd, global_context, raised, &mut self.state, back) #awaited;
+                    parent = id.parent();
+                }
+                match reaction {
+                    __Reaction::Stay => Ok(()),
+                    __Reaction::Change(state, previous) => {
+                        self.remember(previous);
+                        self.notify_change(from, cmd, state.id());
+                        self.change_state(Some(from), state, global_context, raised, now) #awaited
+                    },
+                    __Reaction::Back => {
+                        let state = self.history.pop_back().expect("history is empty");
+                        self.notify_change(from, cmd, state.id());
+                        self.change_state(Some(from), state, global_context, raised, now) #awaited
+                    },
+                    __Reaction::Reject(error) => {
+                        let error = match error {
+                            Error::NoGuard{command, ..} => Error::NoGuard{state: from, command},
+                            Error::Unhandled{command, ..} | Error::NoHistory{command, ..} if no_guard => Error::NoGuard{state: from, command},
+                            Error::NoHistory{command, ..} => Error::NoHistory{state: from, command},
+                            Error::Unhandled{command, ..} if no_history => Error::NoHistory{state: from, command},
+                            Error::Unhandled{command, ..} => Error::Unhandled{state: from, command},
+                            error => error
+                        };
+                        if let Error::Unhandled{..} | Error::NoGuard{..} | Error::NoHistory{..} = error {
+                            return Err(error);
+                        }
+                        self.notify_reject(cmd, &error);
+                        Err(error)
+                    }
+                }
+            }
+
+            // Enter callbacks are executed after old state is left. Machine is in the new state while they run, so
+            // it stays there if they don't complete. If one of them fails, machine is switched to the error state
+            // without execution of its Enter callback. Observers are notified about states machine is in after that.
+            #vis #asyncness fn change_state(&mut self, from: ::std::option::Option<StateId>, new_state: States, global_context: &mut MachineContext, raised: &mut ::std::collections::VecDeque<Commands>, now: ::std::time::Instant) -> ::std::result::Result<(), Error> {
+                let to = new_state.id();
+                self.state = Some(new_state);
+                self.restart_timers(from, to, now);
+                let mut result = enter_parents(from, to, global_context, raised) #awaited;
+                if result.is_ok() {
+                    result = match self.state {
+                        #(Some(States::#nodes{ ref mut context }) => context.enter(global_context, raised) #awaited .map_err(|error| Error::Enter{state: StateId::#nodes, error}),)*
+                        None => unreachable!()
+                    };
+                }
+                #fail_state
+                let to = self.state().id();
+                let entered = entered_states(from, to);
+                for observer in self.observers.iter_mut() {
+                    for state in entered.iter() {
+                        observer.on_enter(*state);
+                    }
+                }
+                self.restart_timers(from, to, now);
+                result
+            }
+            // Timers of entered states are started from now. Timers of states machine stays in keep running.
+            fn restart_timers(&mut self, from: ::std::option::Option<StateId>, to: StateId, now: ::std::time::Instant) {
+                let entered = entered_states(from, to);
+                self.timers.retain(|&(state, _)| to.is_within(state) && !entered.contains(&state));
+                self.timers.extend(entered.into_iter().map(|state| (state, now)));
+            }
+        }
+
+        // Writes states and transitions as PlantUML or Mermaid state diagram. Their syntax differs only in history
+        // state. States are prefixed, so regions can be drawn in one diagram.
+        #vis fn write_statechart(out: &mut ::std::string::String, prefix: &str, initial: StateId, indent: &str, mermaid: bool) {
+            fn write_state(out: &mut ::std::string::String, prefix: &str, state: StateId, indent: &str) {
+                let name = if prefix.is_empty() {
+                    state.to_string()
+                } else {
+                    format!("\"{}\" as {}{}", state, prefix, state)
+                };
+                let children: ::std::vec::Vec<StateId> = STATES.iter().cloned().filter(|child| child.parent() == Some(state)).collect();
+                if children.is_empty() {
+                    out.push_str(&format!("{}state {}\n", indent, name));
+                    return;
+                }
+                out.push_str(&format!("{}state {} {{\n", indent, name));
+                for child in children {
+                    write_state(out, prefix, child, &format!("{}    ", indent));
+                }
+                out.push_str(&format!("{}}}\n", indent));
+            }
+
+            for state in STATES.iter().filter(|state| state.parent().is_none()) {
+                write_state(out, prefix, *state, indent);
+            }
+            let history = if mermaid {
+                format!("{}__history", prefix)
+            } else {
+                ::std::string::String::from("[H]")
+            };
+            if mermaid && TRANSITIONS.iter().any(|transition| match transition.to { Target::Back => true, _ => false }) {
+                out.push_str(&format!("{}state \"H\" as {}\n", indent, history));
+            }
+            out.push_str(&format!("{}[*] --> {}{}\n", indent, prefix, initial));
+            for node in NODES {
+                if node.enter {
+                    out.push_str(&format!("{}{}{} : entry /\n", indent, prefix, node.state));
+                }
+                if node.leave {
+                    out.push_str(&format!("{}{}{} : exit /\n", indent, prefix, node.state));
+                }
+            }
+            for deferral in DEFERRALS {
+                out.push_str(&format!("{}{}{} : {} / defer\n", indent, prefix, deferral.state, deferral.command));
+            }
+            for transition in TRANSITIONS {
+                let label = match transition.guard {
+                    Some(guard) => format!("{} [{}]", transition.command, guard),
+                    None => transition.command.to_string()
+                };
+                match transition.to {
+                    Target::Stay => out.push_str(&format!("{}{}{} : {} / internal\n", indent, prefix, transition.from, label)),
+                    Target::State(state) => out.push_str(&format!("{}{}{} --> {}{} : {}\n", indent, prefix, transition.from, prefix, state, label)),
+                    Target::Back => out.push_str(&format!("{}{}{} --> {} : {}\n", indent, prefix, transition.from, history, label))
+                }
+            }
+            for timeout in TIMEOUTS {
+                out.push_str(&format!("{}{}{} --> {}{} : after {:?}\n", indent, prefix, timeout.from, prefix, timeout.to, timeout.after));
+            }
+        }
+    )
+}
+
+fn single(machine: &Machine, region: &Region) -> TokenStream {
+    let name = &machine.name;
+    let name_string = name_of(name);
+    let context = machine.context.as_ref();
+    let fields = context.map(|context| &context.fields[..]).unwrap_or(&[]);
+    let field_names: Vec<&Ident> = fields.iter().map(|field| &field.name).collect();
+    let types: Vec<_> = fields.iter().map(|field| &field.ty).collect();
+    let common = common(machine);
+    let asynchronous = machine.asyncness.is_some();
+    let (asyncness, awaited) = asyncness(asynchronous);
+    let send = send(asynchronous);
+    let not_started = not_started(&name_string);
+    let core = core(region, TokenStream::new(), context, &name_string, asynchronous);
+    let execute = execute_span(&name_string, asynchronous, code!(
+        let now = self.now();
+        let mut raised = ::std::collections::VecDeque::new();
+        self.dispatch(cmd, &mut raised, now) #awaited?;
+        self.run_to_completion(raised, now) #awaited
+    ));
+    // Command rejected by current state which defers it is kept in pending buffer. It's rejected if the buffer is full.
+    let defer = if region.nodes.iter().any(|node| !node.defers.is_empty()) {
+        code!(
+            let error = if self.region.state().id().is_deferred(cmd.id()) {
+                match self.defer(cmd) {
+                    Ok(()) => {
+                        self.region.notify_defer(cmd);
+                        return Ok(());
+                    },
+                    Err(error) => error
+                }
+            } else {
+                error
+            };
+        )
+    } else {
+        TokenStream::new()
+    };
+    let run_to_completion = run_to_completion(&name_string, asynchronous);
+    let initial = &region.initial.state;
+    let initial_context = init(TokenStream::new(), &region.initial);
+
+    let attrs = &machine.attrs;
+    let vis = &machine.vis;
+    let krate = &machine.krate;
+    let imports = imports(krate);
+    let serde_skip = serde_skip();
+    let serde = serde(&[code!(__Region), code!(MachineContext), code!(Commands)]);
+
+    code!(
+        #(#attrs)*
+        #[allow(non_snake_case)]
+        #[allow(unused_imports)]
+        #[allow(dead_code)]
+        #[allow(unused_variables)]
+        #[allow(unused_mut)]
+        #vis mod #name {
+            use super::*;
+            #imports
+
+            #common
+
+            #core
+
+            #serde
+            pub struct Machine {
+                region: __Region,
+                context: MachineContext,
+                pending: ::std::collections::VecDeque<Commands>,
+                #serde_skip
+                clock: ::std::option::Option<::std::boxed::Box<dyn #krate::Clock #send>>
+            }
+            // If Enter callback of the initial state fails, machine is in the error state. The error is returned
+            // by try_new().
+            pub #asyncness fn new(#(#field_names: #types),*) -> Machine {
+                let (machine, result) = Machine::start(#(#field_names),*) #awaited;
+                if let Err(error) = result {
+                    #not_started
+                }
+                machine
+            }
+            pub #asyncness fn try_new(#(#field_names: #types),*) -> ::std::result::Result<Machine, Error> {
+                let (machine, result) = Machine::start(#(#field_names),*) #awaited;
+                result.map(|_| machine)
+            }
+
+            impl Machine {
+                // Machine enters the initial state and executes Commands raised by its callbacks. The first error of them
+                // is returned.
+                #asyncness fn start(#(#field_names: #types),*) -> (Machine, ::std::result::Result<(), Error>) {
+                    let context = #initial_context;
+                    let machine_context = MachineContext{#(#field_names),*};
+                    let mut machine = Machine{region: __Region::new(), context: machine_context, pending: ::std::collections::VecDeque::new(), clock: None};
+                    let now = machine.now();
+                    let mut raised = ::std::collections::VecDeque::new();
+                    let result = match machine.region.change_state(None, States::#initial{context}, &mut machine.context, &mut raised, now) #awaited {
+                        Ok(()) => machine.run_to_completion(raised, now) #awaited,
+                        Err(error) => Err(error)
+                    };
+                    (machine, result)
+                }
+                // Observer is notified about Commands and state changes of machine.
+                pub fn add_observer<O: Observer #send + 'static>(&mut self, observer: O) {
+                    self.region.add_observer(::std::boxed::Box::new(observer));
+                }
+                // Machine restored from its state and machine-scoped context. The state was entered before, so its
+                // Enter callback is not executed.
+                pub fn from_snapshot(state: States, context: MachineContext) -> Machine {
+                    Machine{region: __Region::restore(state), context, pending: ::std::collections::VecDeque::new(), clock: None}
+                }
+                // Machine takes time from the clock instead of system clock. Timeouts of current state are restarted.
+                pub fn with_clock<C: #krate::Clock #send + 'static>(mut self, clock: C) -> Machine {
+                    self.region.start_timers(clock.now());
+                    self.clock = Some(::std::boxed::Box::new(clock));
+                    self
+                }
+                fn now(&self) -> ::std::time::Instant {
+                    match self.clock {
+                        Some(ref clock) => clock.now(),
+                        None => ::std::time::Instant::now()
+                    }
+                }
+                pub #asyncness fn execute(&mut self, cmd: & Commands) -> ::std::result::Result<(), Error>{
+                    #execute
+                }
+                #asyncness fn dispatch(&mut self, cmd: &Commands, raised: &mut ::std::collections::VecDeque<Commands>, now: ::std::time::Instant) -> ::std::result::Result<(), Error> {
+                    match self.region.execute(cmd, &mut self.context, raised, now) #awaited {
+                        Err(error @ Error::Unhandled{..}) | Err(error @ Error::NoGuard{..}) | Err(error @ Error::NoHistory{..}) => {
+                            #defer
+                            self.region.notify_reject(cmd, &error);
+                            Err(error)
+                        },
+                        result => result
+                    }
+                }
+                #run_to_completion
+                // Deferred Command is kept in order of arrival. Commands are cloned into the buffer, so machine with
+                // deferred Commands requires them to be Clone.
+                fn defer(&mut self, cmd: &Commands) -> ::std::result::Result<(), Error> where for<'a> Commands: ::std::clone::Clone {
+                    if self.pending.len() == PENDING_LIMIT {
+                        return Err(Error::PendingFull{command: cmd.id()});
+                    }
+                    self.pending.push_back(cmd.clone());
+                    Ok(())
+                }
+                // The oldest pending Command which current state reacts on and doesn't defer.
+                fn replayed(&mut self) -> ::std::option::Option<Commands> {
+                    if self.pending.is_empty() {
+                        return None;
+                    }
+                    let state = self.state_id();
+                    let available = state.available_commands();
+                    let position = self.pending.iter().position(|cmd| available.contains(&cmd.id()) && !state.is_deferred(cmd.id()))?;
+                    self.pending.remove(position)
+                }
+                // Deferred Commands in order of arrival.
+                pub fn pending(&self) -> &::std::collections::VecDeque<Commands> {
+                    &self.pending
+                }
+                // Fires timeout of current state or of its parents if it expired by now. Returns true if machine
+                // moved to other state. Machine never polls itself, so it's called by user at next_deadline().
+                pub #asyncness fn poll(&mut self, now: ::std::time::Instant) -> ::std::result::Result<bool, Error> {
+                    let mut raised = ::std::collections::VecDeque::new();
+                    let moved = self.region.poll(now, &mut self.context, &mut raised) #awaited?;
+                    self.run_to_completion(raised, now) #awaited?;
+                    Ok(moved)
+                }
+                // Time the earliest timeout of current state expires at. None if current state has no timeouts.
+                pub fn next_deadline(&self) -> ::std::option::Option<::std::time::Instant> {
+                    self.region.next_deadline()
+                }
+                pub fn current_state(&self) -> &States {
+                    self.region.state()
+                }
+                // Description of machine in DOT language. Diagram is built from State Nodes, so it's always up to date.
+                pub fn to_dot() -> ::std::string::String {
+                    let mut dot = format!("digraph {} {{\n", #name_string);
+                    write_dot(&mut dot, "", StateId::#initial);
+                    dot.push_str("}\n");
+                    dot
+                }
+                // Unreachable states, deadlocks and unused Commands of machine.
+                pub fn analyze() -> Analysis {
+                    analyze()
+                }
+                // Description of machine as PlantUML state diagram.
+                pub fn to_plantuml() -> ::std::string::String {
+                    let mut uml = ::std::string::String::from("@startuml\n");
+                    write_statechart(&mut uml, "", StateId::#initial, "", false);
+                    uml.push_str("@enduml\n");
+                    uml
+                }
+                // Description of machine as Mermaid state diagram.
+                pub fn to_mermaid() -> ::std::string::String {
+                    let mut mermaid = ::std::string::String::from("stateDiagram-v2\n");
+                    write_statechart(&mut mermaid, "", StateId::#initial, "    ", true);
+                    mermaid
+                }
+                // States machine can return to with `back`. The last one is the most recent.
+                pub fn history(&self) -> &::std::collections::VecDeque<States> {
+                    self.region.history()
+                }
+                pub fn state_id(&self) -> StateId {
+                    self.current_state().id()
+                }
+                // True if machine is in this state or in one of its child states.
+                pub fn is_in(&self, state: StateId) -> bool {
+                    self.state_id().is_within(state)
+                }
+                // Commands which have reactions in current state. Guards are not checked, so Command can still be rejected.
+                pub fn available_commands(&self) -> ::std::vec::Vec<CommandId> {
+                    self.state_id().available_commands()
+                }
+                pub fn inner_context(&self) -> &MachineContext {
+                    &self.context
+                }
+                // Only machine-scoped context can be changed from outside. State is changed by Commands only.
+                pub fn inner_context_mut(&mut self) -> &mut MachineContext {
+                    &mut self.context
+                }
+                // Convenience copy of current_state(). Available only if all state contexts are Clone.
+                pub fn get_current_state(&self) -> States where for<'a> States: ::std::clone::Clone {
+                    self.current_state().clone()
+                }
+                // Convenience copy of inner_context(). Available only if machine-scoped context is Clone.
+                pub fn get_inner_context(&self) -> MachineContext where for<'a> MachineContext: ::std::clone::Clone {
+                    self.context.clone()
+                }
+            }
+        }
+    )
+}
+
+// Error of new() which is not returned, try_new() returns it.
+fn not_started(machine: &LitStr) -> TokenStream {
+    event("debug",
+        code!("{}: failed to start: {}", #machine, error),
+        code!(machine = #machine, error = %error, "failed to start"))
+}
+
+// Commands raised by callbacks are executed in order they were raised, each one after the previous one is completed.
+// Error drops the rest of them and is returned. Then pending Commands are replayed. They were sent before, so their
+// errors and errors of Commands raised by them are not returned, but logged. Rejection of them is reported
+// to observers as usual.
+fn run_to_completion(machine: &LitStr, asynchronous: bool) -> TokenStream {
+    let (asyncness, awaited) = asyncness(asynchronous);
+    let failed = event("debug",
+        code!("{}: {:?} failed after replay of pending commands: {}", #machine, cmd, error),
+        code!(machine = #machine, command = ?cmd, error = %error, "failed after replay"));
+    code!(
+        #asyncness fn run_to_completion(&mut self, mut raised: ::std::collections::VecDeque<Commands>, now: ::std::time::Instant) -> ::std::result::Result<(), Error> {
+            let mut executed = 0;
+            let mut replaying = false;
+            loop {
+                let (cmd, result) = match raised.pop_front() {
+                    Some(cmd) if executed == QUEUE_LIMIT => {
+                        let command = cmd.id();
+                        (cmd, Err(Error::Overflow{command}))
+                    },
+                    Some(cmd) => {
+                        executed += 1;
+                        let result = self.dispatch(&cmd, &mut raised, now) #awaited .map(|_| ());
+                        (cmd, result)
+                    },
+                    None => match self.replayed() {
+                        Some(cmd) => {
+                            replaying = true;
+                            let result = self.dispatch(&cmd, &mut raised, now) #awaited .map(|_| ());
+                            (cmd, result)
+                        },
+                        None => return Ok(())
+                    }
+                };
+                if let Err(error) = result {
+                    if !replaying {
+                        return Err(error);
+                    }
+                    #failed
+                    raised.clear();
+                }
+            }
+        }
+    )
+}
+
+// Region of machine with orthogonal regions. It is a module with its own States, StateId and Error.
+fn region_module(machine: &Ident, region: &Region, context: Option<&Context>, asynchronous: bool) -> TokenStream {
+    let name = &region.name;
+    let machine = LitStr::new(&format!("{}::{}", machine, name), name.span());
+    let core = core(region, code!(pub(super)), context, &machine, asynchronous);
+    let finals = &region.finals;
+    code!(
+        pub mod #name {
+            use super::*;
+
+            #core
+
+            impl StateId {
+                // True if this is one of final states of the region or their child states.
+                pub fn is_final(self) -> bool {
+                    #(self.is_within(StateId::#finals) ||)* false
+                }
+            }
+        }
+    )
+}
+
+fn regions_machine(machine: &Machine, join: Option<&syn::Block>, regions: &[Region]) -> TokenStream {
+    let name = &machine.name;
+    let name_string = name_of(name);
+    let context = machine.context.as_ref();
+    let fields = context.map(|context| &context.fields[..]).unwrap_or(&[]);
+    let field_names: Vec<&Ident> = fields.iter().map(|field| &field.name).collect();
+    let types: Vec<_> = fields.iter().map(|field| &field.ty).collect();
+    let common = common(machine);
+    let asynchronous = machine.asyncness.is_some();
+    let (asyncness, awaited) = asyncness(asynchronous);
+    let send = send(asynchronous);
+    let not_started = not_started(&name_string);
+    let modules = regions.iter().map(|region| region_module(name, region, context, asynchronous));
+    // Join callback is executed when all regions reached their final states.
+    let global_context = context_name(context);
+    let join = match join {
+        Some(join) => {
+            let raise = raise(join);
+            code!(#asyncness fn join(#global_context: &mut MachineContext, raised: &mut ::std::collections::VecDeque<Commands>) {#raise #join})
+        },
+        None => code!(#asyncness fn join(#global_context: &mut MachineContext, raised: &mut ::std::collections::VecDeque<Commands>) {}),
+    };
+    let names: Vec<&Ident> = regions.iter().map(|region| &region.name).collect();
+    let add_observers = names.iter().map(|name| Ident::new(&format!("add_{}_observer", name), name.span()));
+    let strings: Vec<LitStr> = names.iter().map(|name| name_of(name)).collect();
+    let execute = execute_span(&name_string, asynchronous, code!(
+        let now = self.now();
+        let mut raised = ::std::collections::VecDeque::new();
+        let handled = match self.dispatch(cmd, &mut raised, now) #awaited {
+            Err(Error::Failed{handled, errors}) => return self.fail(raised, now, handled, errors) #awaited,
+            result => result?
+        };
+        self.run_to_completion(raised, now) #awaited?;
+        Ok(handled)
+    ));
+    // Regions which didn't handle Command report it only if machine rejects it.
+    let rejected: Vec<Ident> = names.iter().map(|name| local(&format!("{}_rejected", name))).collect();
+    // Command which no region handled is kept in pending buffer if current state of any region defers it. Regions
+    // which defer it report it as deferred, or as rejected if the buffer is full.
+    let defer = if regions.iter().any(|region| region.nodes.iter().any(|node| !node.defers.is_empty())) {
+        code!(
+            if self.is_deferred(cmd.id()) {
+                let deferred = self.defer(cmd);
+                #(
+                if self.#names().id().is_deferred(cmd.id()) {
+                    match deferred {
+                        Ok(()) => self.#names.notify_defer(cmd),
+                        Err(_) => self.#names.notify_reject(cmd, &#names::Error::PendingFull{command: cmd.id()})
+                    }
+                } else if let (&Err(_), &Some(ref error)) = (&deferred, &#rejected) {
+                    self.#names.notify_reject(cmd, error);
+                }
+                )*
+                return deferred.map(|_| handled);
+            }
+        )
+    } else {
+        TokenStream::new()
+    };
+    let run_to_completion = run_to_completion(&name_string, asynchronous);
+    let dropped = event("debug",
+        code!("{}: raised commands failed after failure of regions: {}", #name_string, error),
+        code!(machine = #name_string, error = %error, "raised commands failed after failure of regions"));
+    // Command is executed by every region in order of declaration. Regions which have no reactions on it
+    // or none of which guards passed are skipped. Region which failed doesn't stop the others.
+    let dispatch = code!(
+        let joined = self.is_joined();
+        let mut handled = ::std::vec::Vec::new();
+        let mut failed = ::std::vec::Vec::new();
+        #(
+        let mut #rejected = None;
+        match self.#names.execute(cmd, &mut self.context, raised, now) #awaited {
+            Ok(()) => handled.push(RegionId::#names),
+            Err(error @ #names::Error::Unhandled{..}) | Err(error @ #names::Error::NoGuard{..}) | Err(error @ #names::Error::NoHistory{..}) => #rejected = Some(error),
+            Err(error) => failed.push(Error::#names(error))
+        }
+        )*
+        if handled.is_empty() && failed.is_empty() {
+            #defer
+            #(if let Some(ref error) = #rejected {
+                self.#names.notify_reject(cmd, error);
+            })*
+            return Err(Error::Unhandled{command: cmd.id()});
+        }
+        if !joined && self.is_joined() {
+            join(&mut self.context, raised) #awaited;
+        }
+        if !failed.is_empty() {
+            return Err(Error::Failed{handled, errors: failed});
+        }
+        Ok(handled)
+    );
+    let prefixes: Vec<LitStr> = names.iter().map(|name| LitStr::new(&format!("{}::", name), name.span())).collect();
+    let chart_prefixes: Vec<LitStr> = names.iter().map(|name| LitStr::new(&format!("{}_", name), name.span())).collect();
+    let initials: Vec<&Ident> = regions.iter().map(|region| &region.initial.state).collect();
+    let initial_contexts = regions.iter().map(|region| {
+        let name = &region.name;
+        init(code!(#name::), &region.initial)
+    });
+
+    let attrs = &machine.attrs;
+    let vis = &machine.vis;
+    let krate = &machine.krate;
+    let imports = imports(krate);
+    let serde_skip = serde_skip();
+    let serde = serde(&regions.iter().map(|region| {
+        let name = &region.name;
+        code!(#name::__Region)
+    }).chain(vec![code!(MachineContext), code!(Commands)]).collect::<Vec<_>>());
+
+    code!(
+        #(#attrs)*
+        #[allow(non_snake_case)]
+        #[allow(unused_imports)]
+        #[allow(dead_code)]
+        #[allow(unused_variables)]
+        #[allow(unused_mut)]
+        #vis mod #name {
+            use super::*;
+            #imports
+
+            #common
+
+            #(#modules)*
+
+            #join
+
+            /// Identifier of Region.
+            #[derive(Debug)]
+            #[derive(PartialEq)]
+            #[derive(Eq)]
+            #[derive(PartialOrd)]
+            #[derive(Ord)]
+            #[derive(Hash)]
+            #[derive(Copy)]
+            #[derive(Clone)]
+            pub enum RegionId {
+                #(#names),*
+            }
+
+            impl ::std::fmt::Display for RegionId {
+                fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+                    match *self {
+                        #(RegionId::#names => f.write_str(#strings)),*
+                    }
+                }
+            }
+
+            /// Error returned by `Machine::execute`.
+            #[derive(Debug)]
+            pub enum Error {
+                /// Command is not handled by any region.
+                Unhandled{command: CommandId},
+                /// Callbacks raised more Commands during execution of one Command than queue allows.
+                Overflow{command: CommandId},
+                /// Command is deferred, but pending buffer is full. Command is dropped.
+                PendingFull{command: CommandId},
+                /// Some regions failed to execute Command or timeout. Regions in `handled` completed their transitions
+                /// and Commands raised by them were executed. `errors` has an error of each failed region.
+                Failed{handled: ::std::vec::Vec<RegionId>, errors: ::std::vec::Vec<Error>},
+                #(
+                /// Enter/Leave callback of the region failed.
+                #names(#names::Error)
+                ),*
+            }
+
+            impl ::std::fmt::Display for Error {
+                fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+                    match *self {
+                        Error::Unhandled{command} => write!(f, "command {} is not handled in any region", command),
+                        Error::Overflow{command} => write!(f, "queue of raised commands overflowed by command {}", command),
+                        Error::PendingFull{command} => write!(f, "pending buffer is full, command {} is dropped", command),
+                        Error::Failed{ref errors, ..} => {
+                            for (i, error) in errors.iter().enumerate() {
+                                if i > 0 {
+                                    f.write_str("; ")?;
+                                }
+                                write!(f, "{}", error)?;
+                            }
+                            Ok(())
+                        },
+                        #(Error::#names(ref error) => write!(f, "region {}: {}", #strings, error)),*
+                    }
+                }
+            }
+
+            impl ::std::error::Error for Error {
+                fn source(&self) -> ::std::option::Option<&(dyn ::std::error::Error + 'static)> {
+                    match *self {
+                        #(Error::#names(ref error) => ::std::option::Option::Some(error),)*
+                        _ => ::std::option::Option::None
+                    }
+                }
+            }
+
+            /// Result of `Machine::analyze`. Final states of regions are not reported as deadlocks and Commands are
+            /// reported as unused only if no region reacts to them.
+            #[derive(Debug)]
+            #[derive(PartialEq)]
+            #[derive(Eq)]
+            #[derive(Clone)]
+            pub struct Analysis {
+                #(pub #names: #names::Analysis,)*
+                pub unused_commands: ::std::vec::Vec<CommandId>
+            }
+
+            impl Analysis {
+                pub fn is_ok(&self) -> bool {
+                    #(self.#names.unreachable.is_empty() && self.#names.deadlocks.is_empty() &&)* self.unused_commands.is_empty()
+                }
+            }
+
+            #serde
+            pub struct Machine {
+                #(#names: #names::__Region,)*
+                context: MachineContext,
+                pending: ::std::collections::VecDeque<Commands>,
+                #serde_skip
+                clock: ::std::option::Option<::std::boxed::Box<dyn #krate::Clock #send>>
+            }
+            // If Enter callback of the initial state of a region fails, the region is in its error state. The error
+            // is returned by try_new().
+            pub #asyncness fn new(#(#field_names: #types),*) -> Machine {
+                let (machine, result) = Machine::start(#(#field_names),*) #awaited;
+                if let Err(error) = result {
+                    #not_started
+                }
+                machine
+            }
+            pub #asyncness fn try_new(#(#field_names: #types),*) -> ::std::result::Result<Machine, Error> {
+                let (machine, result) = Machine::start(#(#field_names),*) #awaited;
+                result.map(|_| machine)
+            }
+
+            impl Machine {
+                // Regions enter their initial states, then Commands raised by their callbacks are executed.
+                #asyncness fn start(#(#field_names: #types),*) -> (Machine, ::std::result::Result<(), Error>) {
+                    let machine_context = MachineContext{#(#field_names),*};
+                    let mut machine = Machine{#(#names: #names::__Region::new(),)* context: machine_context, pending: ::std::collections::VecDeque::new(), clock: None};
+                    let now = machine.now();
+                    let mut raised = ::std::collections::VecDeque::new();
+                    let mut entered = ::std::vec::Vec::new();
+                    let mut failed = ::std::vec::Vec::new();
+                    #(
+                    let context = #initial_contexts;
+                    match machine.#names.change_state(None, #names::States::#initials{context}, &mut machine.context, &mut raised, now) #awaited {
+                        Ok(()) => entered.push(RegionId::#names),
+                        Err(error) => failed.push(Error::#names(error))
+                    }
+                    )*
+                    if !failed.is_empty() {
+                        let result = machine.fail(raised, now, entered, failed) #awaited .map(|_| ());
+                        return (machine, result);
+                    }
+                    let result = machine.run_to_completion(raised, now) #awaited;
+                    (machine, result)
+                }
+                // Machine restored from states of its regions and machine-scoped context. The states were entered
+                // before, so their Enter callbacks are not executed.
+                pub fn from_snapshot(#(#names: #names::States,)* context: MachineContext) -> Machine {
+                    Machine{#(#names: #names::__Region::restore(#names),)* context, pending: ::std::collections::VecDeque::new(), clock: None}
+                }
+                // Machine takes time from the clock instead of system clock. Timeouts of current states are restarted.
+                pub fn with_clock<C: #krate::Clock #send + 'static>(mut self, clock: C) -> Machine {
+                    let now = clock.now();
+                    #(self.#names.start_timers(now);)*
+                    self.clock = Some(::std::boxed::Box::new(clock));
+                    self
+                }
+                fn now(&self) -> ::std::time::Instant {
+                    match self.clock {
+                        Some(ref clock) => clock.now(),
+                        None => ::std::time::Instant::now()
+                    }
+                }
+                // Command is executed by regions, then Commands raised by their callbacks are. Returns regions which
+                // handled Command.
+                pub #asyncness fn execute(&mut self, cmd: & Commands) -> ::std::result::Result<::std::vec::Vec<RegionId>, Error> {
+                    #execute
+                }
+                #asyncness fn dispatch(&mut self, cmd: &Commands, raised: &mut ::std::collections::VecDeque<Commands>, now: ::std::time::Instant) -> ::std::result::Result<::std::vec::Vec<RegionId>, Error> {
+                    #dispatch
+                }
+                #run_to_completion
+                // Regions which handled Command or timeout completed their transitions, so Commands raised by them are
+                // executed even if other regions failed. Errors of failed regions are returned, error of raised
+                // Command is logged.
+                #asyncness fn fail(&mut self, raised: ::std::collections::VecDeque<Commands>, now: ::std::time::Instant, handled: ::std::vec::Vec<RegionId>, errors: ::std::vec::Vec<Error>) -> ::std::result::Result<::std::vec::Vec<RegionId>, Error> {
+                    if let Err(error) = self.run_to_completion(raised, now) #awaited {
+                        #dropped
+                    }
+                    Err(Error::Failed{handled, errors})
+                }
+                // Deferred Command is kept in order of arrival. Commands are cloned into the buffer, so machine with
+                // deferred Commands requires them to be Clone.
+                fn defer(&mut self, cmd: &Commands) -> ::std::result::Result<(), Error> where for<'a> Commands: ::std::clone::Clone {
+                    if self.pending.len() == PENDING_LIMIT {
+                        return Err(Error::PendingFull{command: cmd.id()});
+                    }
+                    self.pending.push_back(cmd.clone());
+                    Ok(())
+                }
+                // The oldest pending Command which current states of regions react on and doesn't defer.
+                fn replayed(&mut self) -> ::std::option::Option<Commands> {
+                    if self.pending.is_empty() {
+                        return None;
+                    }
+                    let available = self.available_commands();
+                    let position = self.pending.iter().position(|cmd| available.contains(&cmd.id()) && !self.is_deferred(cmd.id()))?;
+                    self.pending.remove(position)
+                }
+                // True if current state of any region defers Command.
+                fn is_deferred(&self, command: CommandId) -> bool {
+                    #(self.#names().id().is_deferred(command) ||)* false
+                }
+                // Deferred Commands in order of arrival.
+                pub fn pending(&self) -> &::std::collections::VecDeque<Commands> {
+                    &self.pending
+                }
+                // Fires expired timeouts of every region. Returns regions which moved to other state.
+                pub #asyncness fn poll(&mut self, now: ::std::time::Instant) -> ::std::result::Result<::std::vec::Vec<RegionId>, Error> {
+                    let joined = self.is_joined();
+                    let mut raised = ::std::collections::VecDeque::new();
+                    let mut moved = ::std::vec::Vec::new();
+                    let mut failed = ::std::vec::Vec::new();
+                    #(
+                    match self.#names.poll(now, &mut self.context, &mut raised) #awaited {
+                        Ok(true) => moved.push(RegionId::#names),
+                        Ok(false) => {},
+                        Err(error) => failed.push(Error::#names(error))
+                    }
+                    )*
+                    if !joined && self.is_joined() {
+                        join(&mut self.context, &mut raised) #awaited;
+                    }
+                    if !failed.is_empty() {
+                        return self.fail(raised, now, moved, failed) #awaited;
+                    }
+                    self.run_to_completion(raised, now) #awaited?;
+                    Ok(moved)
+                }
+                // Time the earliest timeout of current states of regions expires at.
+                pub fn next_deadline(&self) -> ::std::option::Option<::std::time::Instant> {
+                    let deadlines = [#(self.#names.next_deadline()),*];
+                    deadlines.iter().filter_map(|deadline| *deadline).min()
+                }
+                #(
+                pub fn #names(&self) -> &#names::States {
+                    self.#names.state()
+                }
+                // Observer is notified about Commands and state changes of the region.
+                pub fn #add_observers<O: #names::Observer #send + 'static>(&mut self, observer: O) {
+                    self.#names.add_observer(::std::boxed::Box::new(observer));
+                }
+                )*
+                // Description of machine in DOT language. Each region is drawn as a cluster.
+                pub fn to_dot() -> ::std::string::String {
+                    let mut dot = format!("digraph {} {{\n", #name_string);
+                    #(
+                    dot.push_str(&format!("    subgraph cluster_{} {{\n        label=\"{}\";\n", #strings, #strings));
+                    let mut region = ::std::string::String::new();
+                    #names::write_dot(&mut region, #prefixes, #names::StateId::#initials);
+                    for line in region.lines() {
+                        dot.push_str(&format!("    {}\n", line));
+                    }
+                    dot.push_str("    }\n");
+                    )*
+                    dot.push_str("}\n");
+                    dot
+                }
+                // Unreachable states and deadlocks of each region and unused Commands of machine. Commands handled by
+                // other regions are not reported as unused by region.
+                pub fn analyze() -> Analysis {
+                    #(
+                    let mut #names = #names::analyze();
+                    #names.deadlocks.retain(|state| !state.is_final());
+                    )*
+                    let unused_commands: ::std::vec::Vec<CommandId> = COMMANDS.iter().cloned().filter(|command| true #(&& #names.unused_commands.contains(command))*).collect();
+                    #(#names.unused_commands.retain(|command| unused_commands.contains(command));)*
+                    Analysis{#(#names,)* unused_commands}
+                }
+                // Description of machine as PlantUML state diagram. Regions are concurrent regions of machine state.
+                pub fn to_plantuml() -> ::std::string::String {
+                    let mut uml = format!("@startuml\nstate {} {{\n", #name_string);
+                    let mut regions = ::std::vec::Vec::new();
+                    #(
+                    let mut region = ::std::string::String::new();
+                    #names::write_statechart(&mut region, #chart_prefixes, #names::StateId::#initials, "    ", false);
+                    regions.push(region);
+                    )*
+                    uml.push_str(&regions.join("    --\n"));
+                    uml.push_str("}\n@enduml\n");
+                    uml
+                }
+                // Description of machine as Mermaid state diagram. Regions are concurrent regions of machine state.
+                pub fn to_mermaid() -> ::std::string::String {
+                    let mut mermaid = format!("stateDiagram-v2\n    state {} {{\n", #name_string);
+                    let mut regions = ::std::vec::Vec::new();
+                    #(
+                    let mut region = ::std::string::String::new();
+                    #names::write_statechart(&mut region, #chart_prefixes, #names::StateId::#initials, "        ", true);
+                    regions.push(region);
+                    )*
+                    mermaid.push_str(&regions.join("        --\n"));
+                    mermaid.push_str("    }\n");
+                    mermaid
+                }
+                // Commands which have reactions in current state of any region. Guards are not checked.
+                pub fn available_commands(&self) -> ::std::vec::Vec<CommandId> {
+                    let mut available = ::std::vec::Vec::new();
+                    #(available.extend(self.#names().id().available_commands());)*
+                    COMMANDS.iter().cloned().filter(|command| available.contains(command)).collect()
+                }
+                // True if all regions are in their final states.
+                pub fn is_joined(&self) -> bool {
+                    #(self.#names().id().is_final() &&)* true
+                }
+                pub fn inner_context(&self) -> &MachineContext {
+                    &self.context
+                }
+                // Only machine-scoped context can be changed from outside. State is changed by Commands only.
+                pub fn inner_context_mut(&mut self) -> &mut MachineContext {
+                    &mut self.context
+                }
+                // Convenience copy of inner_context(). Available only if machine-scoped context is Clone.
+                pub fn get_inner_context(&self) -> MachineContext where for<'a> MachineContext: ::std::clone::Clone {
+                    self.context.clone()
+                }
+            }
+        }
+    )
+}
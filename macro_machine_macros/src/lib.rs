@@ -0,0 +1,31 @@
+//! Procedural macro behind `declare_machine!` of `macro_machine` crate. Use it through `macro_machine`,
+//! which documents the syntax of machine declaration.
+
+extern crate proc_macro;
+
+mod check;
+mod generate;
+mod parse;
+
+use proc_macro::TokenStream;
+use quote::quote_spanned;
+
+#[proc_macro]
+pub fn declare_machine(input: TokenStream) -> TokenStream {
+    let machine = match syn::parse::<parse::Machine>(input) {
+        Ok(machine) => machine,
+        Err(error) => return compile_errors(error),
+    };
+    if let Err(error) = check::check(&machine) {
+        return compile_errors(error);
+    }
+    generate::machine(&machine).into()
+}
+
+// Errors are reported by compile_error! without path, so it's found in crates of any edition.
+fn compile_errors(errors: syn::Error) -> TokenStream {
+    errors.into_iter().map(|error| {
+        let message = error.to_string();
+        quote_spanned!(error.span()=> compile_error!(#message);)
+    }).collect::<proc_macro2::TokenStream>().into()
+}
@@ -0,0 +1,438 @@
+// Syntax tree of declare_machine! input.
+
+use proc_macro2::TokenStream;
+use quote::quote;
+use syn::parse::{Parse, ParseStream, Peek, Result};
+use syn::punctuated::Punctuated;
+use syn::{braced, bracketed, parenthesized, token, Attribute, Block, Error, Expr, Ident, LitInt, Token, Type, Visibility};
+
+mod kw {
+    syn::custom_keyword!(states);
+    syn::custom_keyword!(commands);
+    syn::custom_keyword!(error);
+    syn::custom_keyword!(history);
+    syn::custom_keyword!(join);
+    syn::custom_keyword!(region);
+    syn::custom_keyword!(back);
+    syn::custom_keyword!(after);
+    syn::custom_keyword!(queue);
+    syn::custom_keyword!(pending);
+    syn::custom_keyword!(defer);
+}
+
+// Attributes and visibility of machine are given to its module. Callbacks of async machine can await.
+pub struct Machine {
+    pub krate: TokenStream,
+    pub attrs: Vec<Attribute>,
+    pub vis: Visibility,
+    pub asyncness: Option<Token![async]>,
+    pub name: Ident,
+    pub context: Option<Context>,
+    pub commands_attrs: Vec<Attribute>,
+    pub commands: Vec<Command>,
+    pub queue: Option<Expr>,
+    pub pending: Option<Expr>,
+    pub body: Body,
+}
+
+// Machine keeps a single region or several orthogonal regions.
+pub enum Body {
+    Single(Box<Region>),
+    Regions {
+        join: Option<Block>,
+        regions: Vec<Region>,
+    },
+}
+
+// Machine-scoped context with its binding name.
+pub struct Context {
+    pub attrs: Vec<Attribute>,
+    pub name: Ident,
+    pub fields: Vec<Field>,
+}
+
+pub struct Field {
+    pub attrs: Vec<Attribute>,
+    pub name: Ident,
+    pub ty: Type,
+}
+
+#[derive(Clone)]
+pub struct FieldValue {
+    pub name: Ident,
+    pub value: Expr,
+}
+
+// State with values of its context fields.
+#[derive(Clone)]
+pub struct Init {
+    pub state: Ident,
+    pub fields: Vec<FieldValue>,
+}
+
+pub struct StateDecl {
+    pub name: Ident,
+    pub parent: Option<Ident>,
+}
+
+pub struct Command {
+    pub attrs: Vec<Attribute>,
+    pub name: Ident,
+    pub fields: Option<Vec<Field>>,
+}
+
+pub struct ErrorState {
+    pub ty: Type,
+    pub state: Init,
+}
+
+// States, their nodes and clauses. Machine without regions has a single region named after machine.
+pub struct Region {
+    pub name: Ident,
+    pub initial: Init,
+    pub states: Vec<StateDecl>,
+    pub finals: Vec<Ident>,
+    pub error: Option<ErrorState>,
+    pub history: Option<Expr>,
+    pub nodes: Vec<Node>,
+}
+
+// Attributes of State Node are given to the state context.
+pub struct Node {
+    pub attrs: Vec<Attribute>,
+    pub state: Ident,
+    pub binding: Option<Ident>,
+    pub fields: Vec<Field>,
+    pub enter: Option<Block>,
+    pub leave: Option<Block>,
+    pub reactions: Vec<Reaction>,
+    pub timeouts: Vec<Timeout>,
+    pub defers: Vec<Ident>,
+}
+
+pub struct Reaction {
+    pub command: Ident,
+    pub bindings: Vec<Ident>,
+    pub guard: Option<Expr>,
+    pub callback: Option<Block>,
+    pub target: Target,
+}
+
+// Keyword `back` is kept to point errors to it.
+pub enum Target {
+    Stay,
+    State(Init),
+    Back(Ident),
+}
+
+// Transition fired when machine stays in the state for the time given by literal with unit: `after 5s => Idle;`.
+pub struct Timeout {
+    pub millis: u64,
+    pub callback: Option<Block>,
+    pub target: Init,
+}
+
+impl Parse for Machine {
+    fn parse(input: ParseStream) -> Result<Self> {
+        // Path to macro_machine crate is given by declare_machine! wrapper.
+        let krate = if input.peek(token::Bracket) {
+            let content;
+            bracketed!(content in input);
+            content.parse()?
+        } else {
+            quote!(::macro_machine)
+        };
+        let attrs = input.call(Attribute::parse_outer)?;
+        let vis = input.parse()?;
+        let asyncness = input.parse()?;
+        let name: Ident = input.parse()?;
+        // Attributes of machine-scoped context. Machine with regions has no initial state, so they are attributes
+        // of Commands if context is not declared.
+        let mut outer = input.call(Attribute::parse_outer)?;
+        let context = if input.peek(Ident) && input.peek2(token::Brace) {
+            let name = input.parse()?;
+            let content;
+            braced!(content in input);
+            let fields = Punctuated::<Field, Token![,]>::parse_terminated(&content)?.into_iter().collect();
+            Some(Context{attrs: ::std::mem::take(&mut outer), name, fields})
+        } else {
+            None
+        };
+
+        if input.peek(token::Paren) {
+            if let Some(attr) = outer.first() {
+                return Err(Error::new_spanned(attr, "attributes must be followed by machine context"));
+            }
+            let content;
+            parenthesized!(content in input);
+            let initial = content.parse()?;
+            let states = parse_states(input)?;
+            let (commands_attrs, commands) = parse_commands(input)?;
+            let queue = parse_limit(input, kw::queue)?;
+            let pending = parse_limit(input, kw::pending)?;
+            let region = parse_region(input, name.clone(), initial, states, Vec::new())?;
+            return Ok(Machine{krate, attrs, vis, asyncness, name, context, commands_attrs, commands, queue, pending, body: Body::Single(Box::new(region))});
+        }
+
+        let (commands_attrs, commands) = parse_commands(input)?;
+        outer.extend(commands_attrs);
+        let queue = parse_limit(input, kw::queue)?;
+        let pending = parse_limit(input, kw::pending)?;
+        let join = if input.peek(kw::join) {
+            input.parse::<kw::join>()?;
+            Some(input.parse()?)
+        } else {
+            None
+        };
+        let mut regions = Vec::new();
+        while !input.is_empty() {
+            input.parse::<kw::region>()?;
+            let name = input.parse()?;
+            let content;
+            parenthesized!(content in input);
+            let initial = content.parse()?;
+            let body;
+            braced!(body in input);
+            let states = parse_states(&body)?;
+            let finals = if body.peek(Token![final]) {
+                body.parse::<Token![final]>()?;
+                let content;
+                bracketed!(content in body);
+                Punctuated::<Ident, Token![,]>::parse_terminated(&content)?.into_iter().collect()
+            } else {
+                Vec::new()
+            };
+            regions.push(parse_region(&body, name, initial, states, finals)?);
+        }
+        Ok(Machine{krate, attrs, vis, asyncness, name, context, commands_attrs: outer, commands, queue, pending, body: Body::Regions{join, regions}})
+    }
+}
+
+fn parse_states(input: ParseStream) -> Result<Vec<StateDecl>> {
+    input.parse::<kw::states>()?;
+    let content;
+    bracketed!(content in input);
+    Ok(Punctuated::<StateDecl, Token![,]>::parse_terminated(&content)?.into_iter().collect())
+}
+
+// Commands with attributes of Commands enum.
+fn parse_commands(input: ParseStream) -> Result<(Vec<Attribute>, Vec<Command>)> {
+    let attrs = input.call(Attribute::parse_outer)?;
+    input.parse::<kw::commands>()?;
+    let content;
+    bracketed!(content in input);
+    Ok((attrs, Punctuated::<Command, Token![,]>::parse_terminated(&content)?.into_iter().collect()))
+}
+
+// Limit of Commands raised by callbacks during execution of one Command, `queue(N)`, or of deferred Commands,
+// `pending(N)`.
+fn parse_limit(input: ParseStream, keyword: impl Peek) -> Result<Option<Expr>> {
+    if !input.peek(keyword) {
+        return Ok(None);
+    }
+    input.parse::<Ident>()?;
+    let content;
+    parenthesized!(content in input);
+    Ok(Some(content.parse()?))
+}
+
+// Clauses and State Nodes following the list of States.
+fn parse_region(input: ParseStream, name: Ident, initial: Init, states: Vec<StateDecl>, finals: Vec<Ident>) -> Result<Region> {
+    let error = if input.peek(kw::error) {
+        input.parse::<kw::error>()?;
+        let content;
+        parenthesized!(content in input);
+        let ty = content.parse()?;
+        content.parse::<Token![=>]>()?;
+        let state = content.parse()?;
+        if !content.is_empty() {
+            return Err(content.error("unexpected tokens after error state"));
+        }
+        Some(ErrorState{ty, state})
+    } else {
+        None
+    };
+    let history = if input.peek(kw::history) {
+        input.parse::<kw::history>()?;
+        let content;
+        parenthesized!(content in input);
+        Some(content.parse()?)
+    } else {
+        None
+    };
+    let mut nodes = Vec::new();
+    while !input.is_empty() && !input.peek(kw::region) {
+        let content;
+        parenthesized!(content in input);
+        nodes.push(content.parse()?);
+    }
+    Ok(Region{name, initial, states, finals, error, history, nodes})
+}
+
+impl Parse for Field {
+    fn parse(input: ParseStream) -> Result<Self> {
+        let attrs = input.call(Attribute::parse_outer)?;
+        let name = input.parse()?;
+        input.parse::<Token![:]>()?;
+        Ok(Field{attrs, name, ty: input.parse()?})
+    }
+}
+
+impl Parse for FieldValue {
+    fn parse(input: ParseStream) -> Result<Self> {
+        let name = input.parse()?;
+        input.parse::<Token![:]>()?;
+        Ok(FieldValue{name, value: input.parse()?})
+    }
+}
+
+impl Parse for Init {
+    fn parse(input: ParseStream) -> Result<Self> {
+        let state = input.parse()?;
+        let fields = if input.peek(token::Brace) {
+            let content;
+            braced!(content in input);
+            Punctuated::<FieldValue, Token![,]>::parse_terminated(&content)?.into_iter().collect()
+        } else {
+            Vec::new()
+        };
+        Ok(Init{state, fields})
+    }
+}
+
+impl Parse for StateDecl {
+    fn parse(input: ParseStream) -> Result<Self> {
+        let name = input.parse()?;
+        let parent = if input.peek(Token![:]) {
+            input.parse::<Token![:]>()?;
+            Some(input.parse()?)
+        } else {
+            None
+        };
+        Ok(StateDecl{name, parent})
+    }
+}
+
+impl Parse for Command {
+    fn parse(input: ParseStream) -> Result<Self> {
+        let attrs = input.call(Attribute::parse_outer)?;
+        let name = input.parse()?;
+        let fields = if input.peek(token::Brace) {
+            let content;
+            braced!(content in input);
+            Some(Punctuated::<Field, Token![,]>::parse_terminated(&content)?.into_iter().collect())
+        } else {
+            None
+        };
+        Ok(Command{attrs, name, fields})
+    }
+}
+
+// State context fields are separated by semicolons.
+impl Parse for Node {
+    fn parse(input: ParseStream) -> Result<Self> {
+        let attrs = input.call(Attribute::parse_outer)?;
+        let state = input.parse()?;
+        let binding = if input.peek(Ident) {
+            Some(input.parse()?)
+        } else {
+            None
+        };
+        let fields = if input.peek(token::Brace) {
+            let content;
+            braced!(content in input);
+            Punctuated::<Field, Token![;]>::parse_terminated(&content)?.into_iter().collect()
+        } else {
+            Vec::new()
+        };
+        input.parse::<Token![:]>()?;
+        let enter = if input.peek(Token![>>]) {
+            input.parse::<Token![>>]>()?;
+            Some(input.parse()?)
+        } else {
+            None
+        };
+        let leave = if input.peek(Token![<<]) {
+            input.parse::<Token![<<]>()?;
+            Some(input.parse()?)
+        } else {
+            None
+        };
+        let mut reactions = Vec::new();
+        let mut timeouts = Vec::new();
+        let mut defers = Vec::new();
+        while !input.is_empty() {
+            if input.peek(kw::after) {
+                timeouts.push(input.parse()?);
+            } else if input.peek(kw::defer) {
+                // Commands kept until machine reaches a state which reacts on them: `defer A, B;`.
+                input.parse::<kw::defer>()?;
+                defers.extend(Punctuated::<Ident, Token![,]>::parse_separated_nonempty(input)?);
+                input.parse::<Token![;]>()?;
+            } else {
+                reactions.push(input.parse()?);
+            }
+        }
+        Ok(Node{attrs, state, binding, fields, enter, leave, reactions, timeouts, defers})
+    }
+}
+
+impl Parse for Timeout {
+    fn parse(input: ParseStream) -> Result<Self> {
+        input.parse::<kw::after>()?;
+        let after: LitInt = input.parse()?;
+        let unit = match after.suffix() {
+            "ms" => 1,
+            "s" => 1000,
+            "m" => 60 * 1000,
+            "h" => 60 * 60 * 1000,
+            _ => return Err(Error::new(after.span(), "timeout must have unit: ms, s, m or h")),
+        };
+        let millis = after.base10_parse::<u64>()?.checked_mul(unit).ok_or_else(|| Error::new(after.span(), "timeout is too long"))?;
+        let callback = if input.peek(token::Brace) {
+            Some(input.parse()?)
+        } else {
+            None
+        };
+        input.parse::<Token![=>]>()?;
+        let target = input.parse()?;
+        input.parse::<Token![;]>()?;
+        Ok(Timeout{millis, callback, target})
+    }
+}
+
+impl Parse for Reaction {
+    fn parse(input: ParseStream) -> Result<Self> {
+        let command = input.parse()?;
+        let bindings = if input.peek(token::Paren) {
+            let content;
+            parenthesized!(content in input);
+            Punctuated::<Ident, Token![,]>::parse_terminated(&content)?.into_iter().collect()
+        } else {
+            Vec::new()
+        };
+        let guard = if input.peek(token::Bracket) {
+            let content;
+            bracketed!(content in input);
+            Some(content.parse()?)
+        } else {
+            None
+        };
+        let callback = if input.peek(token::Brace) {
+            Some(input.parse()?)
+        } else {
+            None
+        };
+        input.parse::<Token![=>]>()?;
+        let target = if input.peek(kw::back) {
+            let back = input.parse::<kw::back>()?;
+            Target::Back(Ident::new("back", back.span))
+        } else if input.peek(Ident) {
+            Target::State(input.parse()?)
+        } else {
+            Target::Stay
+        };
+        input.parse::<Token![;]>()?;
+        Ok(Reaction{command, bindings, guard, callback, target})
+    }
+}
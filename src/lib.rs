@@ -32,7 +32,125 @@
 //! machine.execute(&Simple::Commands::Next).unwrap();
 //! assert!(match machine.get_current_state(){States::B{..}=>true,_=>false});
 //! machine.execute(&Simple::Commands::Next).unwrap();
-//! assert!(match machine.get_current_state(){States::A{..}=>true,_=>false});
+//! assert!(machine.is_in(StateId::A)); // Or just check State identifier
+//! assert_eq!(machine.state_id().to_string(), "A");
+//! # }
+//! ```
+//!
+//! In crates of 2018 and later editions macro is imported by path like any other item,
+//! `#[macro_use] extern crate` is not needed:
+//!
+//! ```edition2018
+//! use macro_machine::declare_machine;
+//!
+//! declare_machine!(
+//!     Simple (A)
+//!     states[A,B]
+//!     commands[Next]
+//!     (A:
+//!         Next => B;
+//!     )
+//!     (B:
+//!         Next => A;
+//!     )
+//! );
+//!
+//! # fn main() {
+//! let mut machine = Simple::new();
+//! machine.execute(&Simple::Commands::Next).unwrap();
+//! assert!(machine.is_in(Simple::StateId::B));
+//! # }
+//! ```
+//!
+//! Macro can be called by full path too:
+//!
+//! ```edition2021
+//! macro_machine::declare_machine!(
+//!     Player log{tracks: Vec<u32>} (Stopped)
+//!     states[Stopped, Active, Playing: Active, Paused: Active]
+//!     commands[Play{track: u32}, Pause, Stop]
+//!     (Stopped:
+//!         Play(track) [*track > 0] => Playing{track: *track};
+//!     )
+//!     (Active:
+//!         Stop => Stopped;
+//!     )
+//!     (Playing context{track: u32}:
+//!         >> {log.tracks.push(context.track);}
+//!         Pause => Paused;
+//!     )
+//!     (Paused:
+//!         Play(track) => Playing{track: *track};
+//!     )
+//! );
+//!
+//! # fn main() {
+//! let mut player = Player::new(Vec::new());
+//! player.execute(&Player::Commands::Play{track: 1}).unwrap();
+//! player.execute(&Player::Commands::Pause).unwrap();
+//! player.execute(&Player::Commands::Play{track: 2}).unwrap();
+//! player.execute(&Player::Commands::Stop).unwrap(); // Inherited from Active
+//! assert_eq!(player.inner_context().tracks, vec![1, 2]);
+//! # }
+//! ```
+//!
+//! Generated code doesn't depend on edition of your crate, 2024 edition is supported as well:
+//!
+//! ```edition2024
+//! use macro_machine::declare_machine;
+//!
+//! declare_machine!(
+//!     Session
+//!     commands[Connect, Login]
+//!     region Link (Down) {
+//!         states[Down, Up]
+//!         final[Up]
+//!         (Down:
+//!             Connect => Up;
+//!         )
+//!         (Up:
+//!         )
+//!     }
+//!     region Auth (Anonymous) {
+//!         states[Anonymous, Authorized]
+//!         final[Authorized]
+//!         (Anonymous:
+//!             Login => Authorized;
+//!         )
+//!         (Authorized:
+//!         )
+//!     }
+//! );
+//!
+//! # fn main() {
+//! let mut session = Session::new();
+//! session.execute(&Session::Commands::Connect).unwrap();
+//! session.execute(&Session::Commands::Login).unwrap();
+//! assert!(session.is_joined());
+//! # }
+//! ```
+//!
+//! Crates of 2015 edition import macro with `#[macro_use] extern crate`:
+//!
+//! ```edition2015
+//! #[macro_use] extern crate macro_machine;
+//!
+//! declare_machine!(
+//!     Simple (A)
+//!     states[A,B]
+//!     commands[Next]
+//!     (A:
+//!         Next => B;
+//!     )
+//!     (B:
+//!         Next => A;
+//!     )
+//! );
+//!
+//! # fn main() {
+//! let mut machine = Simple::new();
+//! machine.execute(&Simple::Commands::Next).unwrap();
+//! assert!(machine.is_in(Simple::StateId::B));
 //! # }
 //! ```
 //!
@@ -156,418 +274,2621 @@
 //! # }
 //! ```
 //!
-
-#[macro_export]
-macro_rules! declare_machine {
-
-    // Initialize state by values
-    (@inner next $new_state:ident{$($new_el:ident:$new_el_val:expr),*}) => (
-        $new_state{$($new_el:$new_el_val),*}
-    );
-
-    // if state have no fields to initialize. Just for remove redundant curl braces.
-    (@inner next $new_state:ident) => (
-        $new_state{}
-    );
-
-    // If Event have user-defined code and move machine to new state. Execute code and return new state.
-    (@inner command @$glob_context:ident@ $sel:ident:$cur:ident;$callback:block;$new_state:ident$({$($new_el:ident:$new_el_val:expr),*})*) => (
-        {
-            declare_machine!(@inner context $sel $cur);
-            $callback;
-            $cur.leave($glob_context).unwrap();
-            Some(States::$new_state{context: declare_machine!(@inner next $new_state$({$($new_el:$new_el_val),*})*)})
-        }
-    );
-
-    // If Event have user-defined code and don't move machine to new state. Execute code and return __SameState__ .
-    (@inner command @$glob_context:ident@ $sel:ident:$cur:ident;$callback:block;) => (
-        {
-            declare_machine!(@inner context $sel $cur);
-            $callback;
-            Some(States::__SameState__)
-        }
-    );
-
-    // If Event have no user-defined code and move machine to new state. Just return new state.
-    (@inner command @$glob_context:ident@ $sel:ident:$cur:ident; ;$new_state:ident$({$($new_el:ident:$new_el_val:expr),*})*) => (
-        {
-            declare_machine!(@inner context $sel $cur);
-            $cur.leave($glob_context).unwrap();
-            Some(States::$new_state{context: declare_machine!(@inner next $new_state$({$($new_el:$new_el_val),*})*)})
-        }
-    );
-
-    // If Event have nothing to do on event. Just return __SameState__.
-    (@inner command @$glob_context:ident@ $sel:ident:$cur:ident ; ;) => (
-        Some(States::__SameState__)
-    );
-
-    // If Event have user-defined code and move machine to new state. Execute code and return new state.
-    (@inner command @$glob_context:ident@ $sel:ident:$cur:ident;$callback:block;$new_state:ident$({$($new_el:ident:$new_el_val:expr),*})*) => (
-        {
-            declare_machine!(@inner context $sel $cur);
-            $callback;
-            $cur.leave($glob_context).unwrap();
-            Some(States::$new_state{context: declare_machine!(@inner next $new_state$({$($new_el:$new_el_val),*})*)})
-        }
-    );
-
-    // If Event have user-defined code and don't move machine to new state. Execute code and return __SameState__ .
-    (@inner command @$glob_context:ident@ $sel:ident:$cur:ident;$callback:block;) => (
-        {
-            declare_machine!(@inner context $sel $cur);
-            $callback;
-            Some(States::__SameState__)
-        }
-    );
-
-    // If Event have no user-defined code and move machine to new state. Just return new state.
-    (@inner command @$glob_context:ident@ $sel:ident:$cur:ident; ;$new_state:ident$({$($new_el:ident:$new_el_val:expr),*})*) => (
-        {
-            declare_machine!(@inner context $sel $cur);
-            $cur.leave($glob_context).unwrap();
-            Some(States::$new_state{context: declare_machine!(@inner next $new_state$({$($new_el:$new_el_val),*})*)})
-        }
-    );
-
-    // If Event have nothing to do on event. Just return __SameState__.
-    (@inner command @$glob_context:ident@ $sel:ident:$cur:ident ; ;) => (
-        Some(States::__SameState__)
-    );
-
-    (@inner context $ss:ident $sel:ident)=>(let $sel = $ss;);
-    (@inner context $ss:ident )=>();
-
-    // Enter/Leave processors with and without user-defined code.
-    (@inner >> $($sel:ident)* @$glob_context:ident@ $income:block) => (
-            fn enter(&mut self, $glob_context: &mut MachineContext) -> Result<(), ()> {
-                declare_machine!(@inner context self $($sel)*);
-                $income
-                Ok(())
-            }
-    );
-    (@inner << $($sel:ident)* @$glob_context:ident@ $outcome:block) => (
-            fn leave(&mut self, $glob_context: &mut MachineContext) -> Result<(), ()> {
-                declare_machine!(@inner context self $($sel)*);
-                $outcome
-                Ok(())
-            }
-    );
-    (@inner >> $($sel:ident)* @$glob_context:ident@ ) => (
-            fn enter(&mut self, $glob_context: &mut MachineContext) -> Result<(), ()> {
-                Ok(())
-            }
-    );
-    (@inner << $($sel:ident)* @$glob_context:ident@ ) => (
-            fn leave(&mut self, $glob_context: &mut MachineContext) -> Result<(), ()> {
-                Ok(())
-            }
-    );
-
-    // This structs keep user-defined contexts for states.
-    (@inner params $state:ident {$($el:ident:$typ:ty);*}) => (
-        #[derive(Debug)]
-        #[derive(PartialEq)]
-        #[derive(Copy)]
-        #[derive(Clone)]
-        pub struct $state {pub $($el:$typ),*}
-    );
-    (@inner params $state:ident) => (
-        #[derive(Debug)]
-        #[derive(PartialEq)]
-        #[derive(Copy)]
-        #[derive(Clone)]
-        pub struct $state {}
-    );
-    (@inner initial $initial:ident{$($init_field:ident:$init_val:expr),*}) => ($initial{$($init_field: $init_val),*});
-    (@inner initial $initial:ident) => ($initial{});
-
-    (@cmd_processor $sel:ident @$glob_context:ident@ ($($cmd:ident $($callback:block)* => $($new_state:ident$({$($new_el:ident:$new_el_val:expr),*})*)*;)*))=>(
-        fn do_job(&mut self, cmd: & Commands, $glob_context: &mut MachineContext) -> Option<States> {
-            match *cmd {
-                $(Commands::$cmd => {declare_machine!(@inner command @$glob_context@ self:$sel;$($callback)*;$($new_state$({$($new_el:$new_el_val),*})*)*)})*
-                _ => None
-            }
-        }
-    );
-
-    (@state $gc_name:ident; $($state:ident @ $sel:ident ; $($income:block)*; ($job:tt); $($outcome:block)*@),*) => (
-        $(
-        impl CanDoJob for $state {
-            declare_machine!(@cmd_processor $sel @$gc_name@ $job);
-            declare_machine!(@inner >> $sel @$gc_name@ $($income)*);
-            declare_machine!(@inner << $sel @$gc_name@ $($outcome)*);
-        }
-        )*
-    );
-    (@state ; $($state:ident @ $sel:ident ; $($income:block)*; ($job:tt); $($outcome:block)* @),*) => (
-        $(
-        impl CanDoJob for $state {
-            declare_machine!(@cmd_processor $sel @__@ $job);
-            declare_machine!(@inner >> $sel @__@ $($income)*);
-            declare_machine!(@inner << $sel @__@ $($outcome)*);
-        }
-        )*
-    );
-
-    (@state $gc_name:ident; $($state:ident@; $($income:block)*; ($job:tt); $($outcome:block)*@),*) => (
-        $(
-        impl CanDoJob for $state {
-            declare_machine!(@cmd_processor ___ @$gc_name@ $job);
-            declare_machine!(@inner >> ___ @$gc_name@ $($income)*);
-            declare_machine!(@inner << ___ @$gc_name@ $($outcome)*);
-        }
-        )*
-    );
-    (@state ; $($state:ident@; $($income:block)*; ($job:tt); $($outcome:block)*@),*) => (
-        $(
-        impl CanDoJob for $state {
-            declare_machine!(@cmd_processor ___ @__@ $job);
-            declare_machine!(@inner >> ___ @__@ $($income)*);
-            declare_machine!(@inner << ___ @__@ $($outcome)*);
-        }
-        )*
-    );
-
-// Main pattern
-
-(
-    $machine:ident $($gc_name:ident{$($context_field:ident:$context_type:ty),*})* ($initial:ident$({$($init_field:ident:$init_val:expr),*})*)
-    states[$($states:ident),*]
-    commands[$($commands:ident),*]
-
-    $(($state:ident $($sel:ident)*$({$($el:ident:$typ:ty);*})*:
-        $(>> $income:block)*
-        $(<< $outcome:block)*
-        $($cmd:ident $($callback:block)* => $($new_state:ident$({$($new_el:ident:$new_el_val:expr),*})*)*;)*
-    ))*
-) => (
-    #[allow(non_snake_case)]
-    #[allow(unused_imports)]
-    #[allow(dead_code)]
-    #[allow(unused_variables)]
-    mod $machine {
-        use super::*;
-        trait CanDoJob {
-            fn do_job(&mut self, cmd: &Commands, global_context: &mut MachineContext) -> Option<States>;
-            fn leave(&mut self, &mut MachineContext) -> Result<(), ()>;
-            fn enter(&mut self, &mut MachineContext) -> Result<(), ()>;
-        }
-
-        $(
-        declare_machine!(@inner params $state $({$($el:$typ);*})*);
-        )*
-
-        declare_machine!(@state $($gc_name)*;$($state @ $($sel)* ; $($income)*; (($($cmd $($callback)* => $($new_state $({$($new_el:$new_el_val),*})*)*;)*)); $($outcome)*@),*);
-
-        #[derive(Debug)]
-        #[derive(PartialEq)]
-        #[derive(Copy)]
-        #[derive(Clone)]
-        pub enum States {
-            __SameState__,
-            $($states {context: $states}),*
-        }
-
-        #[derive(Debug)]
-        #[derive(PartialEq)]
-        pub enum Commands {
-            $($commands),*
-        }
-
-        #[derive(Clone)]
-        pub struct MachineContext {$($(pub $context_field: $context_type),*)*}
-
-        pub struct Machine {
-            state: States,
-            context: MachineContext
-        }
-        pub fn new($($($context_field: $context_type),*)*) -> Machine {
-            let mut context = declare_machine!(@inner initial $initial $({$($init_field: $init_val),*})*);
-            let mut machine_context = MachineContext{$($($context_field: $context_field),*)*};
-            context.enter(&mut machine_context).unwrap();
-            Machine{state: States::$initial{context: context}, context: machine_context}
-        }
-
-        impl Machine {
-            pub fn execute(&mut self, cmd: & Commands) -> Result<(),()>{
-                match {
-                    match self.state {
-                        States::__SameState__ => None,
-                        $(States::$state{ ref mut context } => context.do_job(cmd, &mut self.context)),*
-                    }
-                } {
-                    Some(x) => {
-                        match x {
-                            States::__SameState__ => {},
-                            _ => {
-                                self.change_state(x)
-                            }
-                        };Ok(())
-                    },
-                    None => {println!("Wrong operation {:?} for {:?} state!", cmd, self.state); Err(())}
-                }
-            }
-            fn change_state(&mut self, new_state: States) {
-                self.state = new_state;
-                match self.state {
-                    States::__SameState__ => Ok(()),
-                    $(States::$state{ ref mut context } => context.enter(&mut self.context)),*
-                }.unwrap();
-            }
-            pub fn get_current_state(&self) -> States {
-                self.state.clone()
-            }
-            pub fn get_inner_context(&self) -> MachineContext {
-                self.context.clone()
-            }
-        }
-    }
-)
-}
-
-#[cfg(test)]
-mod tests {
-    fn tes(x:i16) {
-        println!("x:{}",x);
-    }
-
-    declare_machine!(
-    Mach1 (New{x:0})
-
-    states[New,InConfig,Operational]
-    commands[Configure, ConfigureDone, Drop]
-
-    ( New context{x: i16}:
-        >> {println!("Enter {:?}", context)}
-        << {println!("Leave {:?}", context)}
-        Configure     {println!("In New with context val: {}", context.x);} =>     InConfig{x:context.x+1, y:0};
-        ConfigureDone => New{x:0};
-    )
-    ( InConfig context{x:i16; y:i16}:
-        >> {println!("Enter {:?}", context)}
-        << {println!("Leave {:?}", context)}
-        ConfigureDone {tes(context.x)}=> Operational;
-    )
-    ( Operational context:
-        >> {println!("Enter {:?}", context)}
-        << {println!("Leave {:?}", context)}
-        ConfigureDone =>;
-        Drop => New{x:0};
-    )
-    );
-
-    declare_machine!(
-    Mach2 (State1)
-
-    states[State1,State2,State3]
-    commands[ToState1, ToState2, ToState3]
-
-    ( State1 :
-        ToState2 => State2;
-    )
-    ( State2 :
-        ToState3 => State3;
-    )
-    ( State3 :
-        ToState1 => State1;
-    )
-    );
-
-    #[test]
-    fn test1() {
-        let mut m = Mach1::new();
-        m.execute(&Mach1::Commands::Configure).unwrap();
-        m.execute(&Mach1::Commands::ConfigureDone).unwrap();
-        m.execute(&Mach1::Commands::Drop).unwrap();
-        m.execute(&Mach1::Commands::Configure).unwrap();
-        m.execute(&Mach1::Commands::ConfigureDone).unwrap();
-        m.execute(&Mach1::Commands::ConfigureDone).unwrap();
-        m.execute(&Mach1::Commands::ConfigureDone).unwrap();
-    }
-
-    #[test]
-    #[should_panic]
-    fn test2() {
-        let mut m = Mach2::new();
-        m.execute(&Mach2::Commands::ToState2).unwrap();
-        m.execute(&Mach2::Commands::ToState3).unwrap();
-        m.execute(&Mach2::Commands::ToState1).unwrap();
-        m.execute(&Mach2::Commands::ToState3).unwrap();
-    }
-
-    declare_machine!(
-    Mach3 glob_cont{id:i16}(State1{counter:0})
-
-    states[State1,State2,State3]
-    commands[ToState1, ToState2, ToState3]
-
-    ( State1 cont{counter:i16}:
-        >>{println!("Mach {} enter {:?}", glob_cont.id, cont);}
-        <<{cont.counter+=1; println!("Mach {} leave {:?}", glob_cont.id, cont);}
-        ToState2 => State2{counter: cont.counter};
-    )
-    ( State2 cont{counter:i16}:
-        >>{println!("Mach {} enter {:?}", glob_cont.id, cont);}
-        <<{cont.counter+=1; println!("Mach {} leave {:?}", glob_cont.id, cont);}
-        ToState3 => State3{counter: cont.counter};
-    )
-    ( State3 cont{counter:i16}:
-        >>{println!("Mach {} enter {:?}", glob_cont.id, cont);}
-        <<{cont.counter+=1; println!("Mach {} leave {:?}", glob_cont.id, cont);}
-        ToState1 => State1{counter: cont.counter};
-    )
-    );
-
-    #[test]
-    fn test3() {
-        let mut m = Mach3::new(0);
-        let mut m1 = Mach3::new(1);
-        m1.execute(&Mach3::Commands::ToState2).unwrap();
-        m.execute(&Mach3::Commands::ToState2).unwrap();
-        m.execute(&Mach3::Commands::ToState3).unwrap();
-        m1.execute(&Mach3::Commands::ToState3).unwrap();
-    }
-
-    #[derive(Clone)]
-    pub struct InnerMachineContext {
-        id: i16,
-        name: String,
-        counter: i16
-    }
-
-    declare_machine!(
-        Mach4 inner{st: InnerMachineContext} (State1)
-        states[State1,State2,State3]
-        commands[ToState1, ToState2, ToState3]
-
-        ( State1 :
-            << {println!("id={} name={} counter={}", inner.st.id, inner.st.name, inner.st.counter);}
-            ToState2 {inner.st.counter+=1;}=> State2;
-        )
-        ( State2 :
-            << {println!("id={} name={} counter={}", inner.st.id, inner.st.name, inner.st.counter);}
-            ToState3 {inner.st.counter+=1;}=> State3;
-        )
-        ( State3 :
-            << {println!("id={} name={} counter={}", inner.st.id, inner.st.name, inner.st.counter);}
-            ToState1 {inner.st.counter+=1;}=> State1;
-        )
-    );
-    #[test]
-    fn test4() {
-        let mut m = Mach4::new(InnerMachineContext{id:0, name: String::from("Mach 0"), counter: 0});
-        let mut m1 = Mach4::new(InnerMachineContext{id:1, name: String::from("Mach 1"), counter: 0});
-        let mut m2 = Mach4::new(InnerMachineContext{id:2, name: String::from("Mach 2"), counter: 0});
-        m.execute(&Mach4::Commands::ToState2).unwrap();
-        m.execute(&Mach4::Commands::ToState3).unwrap();
-        m1.execute(&Mach4::Commands::ToState2).unwrap();
-        m.execute(&Mach4::Commands::ToState1).unwrap();
-        m1.execute(&Mach4::Commands::ToState3).unwrap();
-        m2.execute(&Mach4::Commands::ToState2).unwrap();
-        m.execute(&Mach4::Commands::ToState2).unwrap();
+//! Command Reaction can have a guard. Reactions on the same Command are checked in order of
+//! declaration and the first one with passing guard is executed:
+//!
+//! ```
+//! #[macro_use] extern crate macro_machine;
+//!
+//! declare_machine!(
+//!     Simple (A{counter:0})
+//!     states[A,B]
+//!     commands[Next]
+//!     (A context{counter:i16}:
+//!         Next [context.counter >= 2] => B; // Guarded Command Reaction. Change state to B only when counter reached 2
+//!         Next {context.counter=context.counter+1} =>; // Otherwise just count
+//!     )
+//!     (B context:
+//!         Next [false] => A{counter:0}; // Guard never passes. Command is reported as failed
+//!     )
+//! );
+//!
+//! # fn main() {
+//! use Simple::*;
+//!
+//! let mut machine = Simple::new();
+//! machine.execute(&Simple::Commands::Next).unwrap();
+//! machine.execute(&Simple::Commands::Next).unwrap();
+//! machine.execute(&Simple::Commands::Next).unwrap();
+//! assert!(match machine.get_current_state(){States::B{..}=>true,_=>false});
+//! assert!(machine.execute(&Simple::Commands::Next).is_err());
+//! # }
+//! ```
+//!
+//! `execute` returns generated `Error` type, which tells what Command was rejected in which State:
+//!
+//! ```
+//! #[macro_use] extern crate macro_machine;
+//!
+//! declare_machine!(
+//!     Simple (A)
+//!     states[A,B]
+//!     commands[Next,Back]
+//!     (A:
+//!         Next => B;
+//!     )
+//!     (B:
+//!         Next [false] => A; // Guard never passes
+//!     )
+//! );
+//!
+//! # fn main() {
+//! use Simple::*;
+//!
+//! let mut machine = Simple::new();
+//! assert!(match machine.execute(&Simple::Commands::Back){
+//!     Err(Error::Unhandled{state: StateId::A, command: CommandId::Back})=>true, // No reactions on Back in A
+//!     _=>false
+//! });
+//! machine.execute(&Simple::Commands::Next).unwrap();
+//! assert!(match machine.execute(&Simple::Commands::Next){
+//!     Err(Error::NoGuard{state: StateId::B, command: CommandId::Next})=>true, // There are reactions on Next in B, but no guard passed
+//!     _=>false
+//! });
+//! # }
+//! ```
+//!
+//! Enter and Leave callbacks can fail. Declare type of their error and the error state:
+//!
+//! * If Leave callback fails, transition is aborted and machine stays in the old State. Changes made to the
+//!   State context and machine-scoped context by Command Reaction and Leave callback are kept, Commands raised
+//!   by them are dropped. Contexts are not copied before transition, so they don't have to implement `Clone`.
+//!   Leave callback should check whether the State can be left before it changes contexts.
+//! * If Enter callback fails, old State is already left, so machine is switched to the error State.
+//!   Enter callback of the error State is not executed. This is also applied to the initial State:
+//!   `new()` returns machine in the error State, `try_new()` returns the error instead of machine.
+//!
+//! Without declared error Enter and Leave callbacks can't fail. `Error` implements `std::error::Error`, and if the
+//! declared error type implements it too, error of the callback is the `source()` of `Error::Enter` and `Error::Leave`.
+//!
+//! ```
+//! #[macro_use] extern crate macro_machine;
+//!
+//! declare_machine!(
+//!     Simple (A{ready:false})
+//!     states[A,B,Failed]
+//!     commands[Next,Prepare,Reset]
+//!     error(String => Failed) // Error type of callbacks and the error State
+//!     (A context{ready:bool}:
+//!         << {if !context.ready {return Err(String::from("not ready"));}}
+//!         Prepare {context.ready = true;} =>;
+//!         Next => B;
+//!     )
+//!     (B context:
+//!         >> {return Err(String::from("broken"));}
+//!         Reset => A{ready:false};
+//!     )
+//!     (Failed context:
+//!         Reset => A{ready:false};
+//!     )
+//! );
+//!
+//! # fn main() {
+//! use Simple::*;
+//!
+//! let mut machine = Simple::new();
+//! assert!(match machine.execute(&Simple::Commands::Next){
+//!     Err(Error::Leave{state: StateId::A, ..})=>true, // Leave failed. We are still in A
+//!     _=>false
+//! });
+//! machine.execute(&Simple::Commands::Prepare).unwrap();
+//! assert!(match machine.execute(&Simple::Commands::Next){
+//!     Err(Error::Enter{state: StateId::B, ..})=>true, // Enter failed. We are in Failed now
+//!     _=>false
+//! });
+//! assert!(match machine.get_current_state(){States::Failed{..}=>true,_=>false});
+//! # }
+//! ```
+//!
+//! State context can keep values of any type. On transition context of the old State is moved,
+//! not copied, so its fields can be used to initialize the next State. `States` implements `Debug`, `Clone`
+//! and `PartialEq` only if all state contexts implement them:
+//!
+//! ```
+//! #[macro_use] extern crate macro_machine;
+//!
+//! declare_machine!(
+//!     Simple (A{name: String::from("machine"), data: vec![1, 2, 3]})
+//!     states[A,B]
+//!     commands[Next]
+//!     (A context{name: String; data: Vec<u8>}:
+//!         Next {context.data.push(4);} => B{name: context.name, data: Box::new(context.data)}; // Move name and data to B
+//!     )
+//!     (B context{name: String; data: Box<Vec<u8>>}:
+//!         Next => A{name: context.name, data: *context.data};
+//!     )
+//! );
+//!
+//! # fn main() {
+//! use Simple::*;
+//!
+//! let mut machine = Simple::new();
+//! machine.execute(&Simple::Commands::Next).unwrap();
+//! assert!(match *machine.current_state(){ // Borrow current State instead of cloning it
+//!     States::B{ref context}=> context.name == "machine" && *context.data == vec![1, 2, 3, 4],
+//!     _=>false
+//! });
+//! # }
+//! ```
+//!
+//! `current_state()` and `inner_context()` borrow current State and machine-scoped context.
+//! Machine-scoped context can also be changed with `inner_context_mut()`. `get_current_state()` and
+//! `get_inner_context()` return their copies and are available only for `Clone` contexts.
+//!
+//! States can be nested. Child State is declared as `Child: Parent` in the list of States.
+//! Child State inherits Command Reactions of its parents unless it reacts on the Command itself
+//! or none of its guards passed. On transition machine leaves current State and its parents
+//! up to the common parent with the new State, then enters parents of the new State and the new State.
+//! Parent States can't have context. Machine can be in parent State too:
+//!
+//! ```
+//! #[macro_use] extern crate macro_machine;
+//!
+//! declare_machine!(
+//!     Simple machine_context{log: Vec<&'static str>} (New)
+//!     states[New, Connected, Idle: Connected, Busy: Connected] // Idle and Busy are inside of Connected
+//!     commands[Connect, Send, Done, Drop]
+//!     (New:
+//!         Connect => Idle;
+//!     )
+//!     (Connected:
+//!         >> {machine_context.log.push("enter Connected");}
+//!         << {machine_context.log.push("leave Connected");}
+//!         Drop => New; // Inherited by Idle and Busy
+//!     )
+//!     (Idle:
+//!         Send => Busy; // Connected is not left
+//!     )
+//!     (Busy:
+//!         Done => Idle;
+//!     )
+//! );
+//!
+//! # fn main() {
+//! use Simple::*;
+//!
+//! let mut machine = Simple::new(Vec::new());
+//! machine.execute(&Simple::Commands::Connect).unwrap();
+//! machine.execute(&Simple::Commands::Send).unwrap();
+//! assert!(machine.is_in(StateId::Busy));
+//! assert!(machine.is_in(StateId::Connected)); // Busy is inside of Connected
+//! machine.execute(&Simple::Commands::Drop).unwrap();
+//! assert_eq!(machine.inner_context().log, vec!["enter Connected", "leave Connected"]);
+//! # }
+//! ```
+//!
+//! Machine definition is checked at compile time. Undeclared State or Command, State or Command
+//! declared twice, State without State Node, State Node declared twice, binding of unknown payload
+//! field, reaction which is never executed because previous reaction on the same Command has no
+//! guard, parent States forming a cycle, parent State with context fields and State named like an item of
+//! generated module, such as `Error` or `StateId`, are reported by name and point to the offending token:
+//!
+//! ```compile_fail
+//! #[macro_use] extern crate macro_machine;
+//!
+//! declare_machine!(
+//!     Simple (A)
+//!     states[A,B]
+//!     commands[Next]
+//!     (A:
+//!         Next => C; // error: state `C` in reaction of state `A` is not declared in states[...]
+//!     )
+//!     (B:
+//!         Next => A;
+//!         Next => B; // error: reaction on command `Next` in state `B` is unreachable: previous reaction on it has no guard
+//!     )
+//! );
+//!
+//! # fn main() {}
+//! ```
+//!
+//! Commands can carry data. Declare payload fields next to the command and bind them by name in
+//! the Command Reaction. Fields are bound by reference:
+//!
+//! ```
+//! #[macro_use] extern crate macro_machine;
+//!
+//! declare_machine!(
+//!     Simple (Idle)
+//!     states[Idle,Configured]
+//!     commands[Configure{addr: u32, port: u16}, Reset] // Command with payload
+//!     (Idle context:
+//!         Configure(addr, port) => Configured{addr: *addr, port: *port}; // Bind payload fields and pass them to the next State
+//!     )
+//!     (Configured context{addr: u32; port: u16}:
+//!         Configure(port) {context.port = *port;} =>; // Bind only the fields you need
+//!         Reset => Idle;
+//!     )
+//! );
+//!
+//! # fn main() {
+//! use Simple::*;
+//!
+//! let mut machine = Simple::new();
+//! machine.execute(&Simple::Commands::Configure{addr: 1, port: 80}).unwrap();
+//! machine.execute(&Simple::Commands::Configure{addr: 2, port: 8080}).unwrap();
+//! assert!(match machine.get_current_state(){
+//!     States::Configured{context}=> context.addr == 1 && context.port == 8080,
+//!     _=>false
+//! });
+//! # }
+//! ```
+//!
+//! Machine can be drawn from its definition. `Machine::to_dot()` describes States, initial State
+//! and Command Reactions in DOT language, so diagram can be rendered by Graphviz and never drifts from
+//! the code. Reactions without the next State are drawn as self-loops, guards are added to labels and
+//! child States are drawn inside of their parents:
+//!
+//! ```
+//! #[macro_use] extern crate macro_machine;
+//!
+//! declare_machine!(
+//!     Simple (A{counter:0})
+//!     states[A,B]
+//!     commands[Next]
+//!     (A context{counter:i16}:
+//!         Next [context.counter >= 2] => B;
+//!         Next {context.counter=context.counter+1} =>;
+//!     )
+//!     (B context:
+//!         Next => A{counter:0};
+//!     )
+//! );
+//!
+//! # fn main() {
+//!     let dot = Simple::Machine::to_dot(); // Render with `dot -Tsvg`
+//!     assert!(dot.starts_with("digraph Simple {"));
+//!     assert!(dot.contains("\"__start\" -> \"A\";")); // Initial State
+//!     assert!(dot.contains("\"A\" -> \"B\" [label=\"Next [context.counter >= 2]\"];"));
+//!     assert!(dot.contains("\"A\" -> \"A\" [label=\"Next\"];")); // Self-loop
+//! # }
+//! ```
+//!
+//! The same description is available as PlantUML and Mermaid state diagram with `Machine::to_plantuml()`
+//! and `Machine::to_mermaid()`. States with Enter and Leave callbacks are marked with `entry` and `exit`,
+//! Command Reactions without the next State are marked as `internal`:
+//!
+//! ```
+//! #[macro_use] extern crate macro_machine;
+//!
+//! declare_machine!(
+//!     Simple (A)
+//!     states[A,B]
+//!     commands[Next,Ping]
+//!     (A:
+//!         >> {println!("Enter A");}
+//!         Next => B;
+//!         Ping =>;
+//!     )
+//!     (B:
+//!         Next => A;
+//!     )
+//! );
+//!
+//! # fn main() {
+//!     assert_eq!(Simple::Machine::to_plantuml(), "@startuml\n\
+//!         state A\n\
+//!         state B\n\
+//!         [*] --> A\n\
+//!         A : entry /\n\
+//!         A --> B : Next\n\
+//!         A : Ping / internal\n\
+//!         B --> A : Next\n\
+//!         @enduml\n");
+//!     assert!(Simple::Machine::to_mermaid().starts_with("stateDiagram-v2\n"));
+//! # }
+//! ```
+//!
+//! Generated module describes machine at runtime. `STATES` and `COMMANDS` list all States and Commands,
+//! `TRANSITIONS` lists all Command Reactions with their guards and targets. `available_commands()` returns
+//! Commands which have reactions in current State or its parents. Guards are not checked:
+//!
+//! ```
+//! #[macro_use] extern crate macro_machine;
+//!
+//! declare_machine!(
+//!     Simple (A)
+//!     states[A,B]
+//!     commands[Next,Back,Reset]
+//!     (A:
+//!         Next => B;
+//!     )
+//!     (B:
+//!         Back => A;
+//!         Reset [false] => A;
+//!     )
+//! );
+//!
+//! # fn main() {
+//!     use Simple::*;
+//!
+//!     assert_eq!(Simple::STATES, &[StateId::A, StateId::B]);
+//!     assert_eq!(Simple::COMMANDS, &[CommandId::Next, CommandId::Back, CommandId::Reset]);
+//!     assert_eq!(Simple::TRANSITIONS[0], Transition{from: StateId::A, command: CommandId::Next, guard: None, to: Target::State(StateId::B)});
+//!     assert_eq!(Simple::TRANSITIONS[2].guard, Some("false"));
+//!
+//!     let mut machine = Simple::new();
+//!     assert_eq!(machine.available_commands(), vec![CommandId::Next]);
+//!     machine.execute(&Simple::Commands::Next).unwrap();
+//!     assert_eq!(machine.available_commands(), vec![CommandId::Back, CommandId::Reset]);
+//! # }
+//! ```
+//!
+//! `Machine::analyze()` walks Command Reactions from the initial State and reports States which can't be
+//! reached, States which can't be left and Commands which no State reacts to. Guards are not checked. Machine with
+//! regions reports them per region, but final States are not deadlocks and Commands are unused only if no region
+//! reacts to them.
+//! It can be used in tests to keep growing machine consistent:
+//!
+//! ```
+//! #[macro_use] extern crate macro_machine;
+//!
+//! declare_machine!(
+//!     Simple (A)
+//!     states[A,B,C,D]
+//!     commands[Next,Skip]
+//!     (A:
+//!         Next => B;
+//!     )
+//!     (B:
+//!         Next => C;
+//!     )
+//!     (C:
+//!         Next =>; // Machine can't leave C
+//!     )
+//!     (D: // No reactions lead to D
+//!         Next => A;
+//!     )
+//! );
+//!
+//! # fn main() {
+//!     use Simple::*;
+//!
+//!     let analysis = Simple::Machine::analyze();
+//!     assert_eq!(analysis.unreachable, vec![StateId::D]);
+//!     assert_eq!(analysis.deadlocks, vec![StateId::C]);
+//!     assert_eq!(analysis.unused_commands, vec![CommandId::Skip]);
+//!     assert!(!analysis.is_ok());
+//! # }
+//! ```
+//!
+//! Command Reaction can move machine `back` to the previous State. Machine keeps States it left
+//! in history, if history depth is declared. Context of the State is restored as it was when the State was
+//! left, and its Enter callback is executed again. When history is full, the oldest State is dropped.
+//! Reaction with `back` is tried only if history is not empty. If all reactions on the Command are `back` and
+//! history is empty, `execute` returns `Error::NoHistory`. Keeping history requires all State
+//! contexts to be `Clone`. Without `history(N)` there is no State to return to, so `back` is reported
+//! at compile time:
+//!
+//! ```
+//! #[macro_use] extern crate macro_machine;
+//!
+//! declare_machine!(
+//!     Simple (Playing{position:0})
+//!     states[Playing, Paused, Menu]
+//!     commands[Tick, Pause, Resume, Open]
+//!     history(1) // Keep only one State
+//!     (Playing context{position:u32}:
+//!         Tick {context.position += 1;} =>;
+//!         Pause => Paused;
+//!     )
+//!     (Paused:
+//!         Resume => back; // Restore Playing with its position
+//!         Open => Menu;
+//!     )
+//!     (Menu:
+//!         Resume => back;
+//!     )
+//! );
+//!
+//! # fn main() {
+//!     use Simple::*;
+//!
+//!     let mut machine = Simple::new();
+//!     machine.execute(&Simple::Commands::Tick).unwrap();
+//!     machine.execute(&Simple::Commands::Pause).unwrap();
+//!     machine.execute(&Simple::Commands::Resume).unwrap();
+//!     assert!(match *machine.current_state(){
+//!         States::Playing{ref context}=> context.position == 1,
+//!         _=>false
+//!     });
+//!     machine.execute(&Simple::Commands::Pause).unwrap();
+//!     machine.execute(&Simple::Commands::Open).unwrap();
+//!     machine.execute(&Simple::Commands::Resume).unwrap(); // Back to Paused. Playing was dropped from history
+//!     assert_eq!(machine.state_id(), StateId::Paused);
+//!     assert!(machine.history().is_empty());
+//!     assert!(machine.execute(&Simple::Commands::Resume).is_err()); // Nothing to return to
+//! # }
+//! ```
+//!
+//! ```compile_fail
+//! #[macro_use] extern crate macro_machine;
+//!
+//! declare_machine!(
+//!     Simple (Playing)
+//!     states[Playing, Paused]
+//!     commands[Pause, Resume]
+//!     (Playing:
+//!         Pause => Paused;
+//!     )
+//!     (Paused:
+//!         Resume => back; // error: reaction on command `Resume` in state `Paused` moves back, but history(N) is not declared
+//!     )
+//! );
+//!
+//! # fn main() {}
+//! ```
+//!
+//! Machine can have several orthogonal regions which are active at the same time. Each region has
+//! its own initial State, States and State Nodes. Command is executed by every region and `execute`
+//! returns regions which handled it. It fails if no region handled the Command. Region which failed to change
+//! its State doesn't stop the others: Command is executed by all regions, Commands raised by them are executed and
+//! `Error::Failed` lists regions which handled the Command and error of each failed region. Timeouts fired by
+//! `poll()` are handled the same way. Region is a module with its own `States`, `StateId` and `Error`. Its final
+//! States are declared with `final`. When all regions reach their final States, `join` callback is
+//! executed:
+//!
+//! ```
+//! #[macro_use] extern crate macro_machine;
+//!
+//! declare_machine!(
+//!     Session machine_context{joined: bool}
+//!     commands[Connect, Login, Logout, Disconnect] // No initial State. Commands are shared by all regions
+//!     join {machine_context.joined = true;} // Executed when all regions reached final States
+//!     region Link (Down) { // Region with its initial State
+//!         states[Down, Up]
+//!         final[Down]
+//!         (Down:
+//!             Connect => Up;
+//!         )
+//!         (Up:
+//!             Disconnect => Down;
+//!         )
+//!     }
+//!     region Auth (Anonymous) {
+//!         states[Anonymous, Authorized]
+//!         final[Anonymous]
+//!         (Anonymous:
+//!             Login => Authorized;
+//!         )
+//!         (Authorized:
+//!             Logout => Anonymous;
+//!             Disconnect => Anonymous;
+//!         )
+//!     }
+//! );
+//!
+//! # fn main() {
+//!     use Session::*;
+//!
+//!     let mut machine = Session::new(false);
+//!     machine.execute(&Session::Commands::Connect).unwrap();
+//!     machine.execute(&Session::Commands::Login).unwrap();
+//!     assert_eq!(machine.Link().id(), Link::StateId::Up); // Current State of region
+//!     assert_eq!(machine.Auth().id(), Auth::StateId::Authorized);
+//!     let handled = machine.execute(&Session::Commands::Disconnect).unwrap();
+//!     assert_eq!(handled, vec![RegionId::Link, RegionId::Auth]); // Both regions handled Disconnect
+//!     assert!(machine.is_joined());
+//!     assert!(machine.inner_context().joined);
+//! # }
+//! ```
+//!
+//! Region is kept in a field of machine and its State is returned by a method of the same name, so region can't be
+//! named like other fields and methods of machine, such as `context` or `execute`:
+//!
+//! ```compile_fail
+//! #[macro_use] extern crate macro_machine;
+//!
+//! declare_machine!(
+//!     Session
+//!     commands[Connect]
+//!     region context (Down) { // error: region name `context` is reserved by generated machine, rename the region
+//!         states[Down, Up]
+//!         (Down:
+//!             Connect => Up;
+//!         )
+//!         (Up: )
+//!     }
+//! );
+//!
+//! # fn main() {}
+//! ```
+//!
+//! Machine can be exported with visibility qualifier before its name. Outer attributes, such as doc
+//! comments, `#[cfg]` or `#[derive]`, are forwarded to generated items: attributes before the machine name go
+//! to its module, before machine-scoped context to `MachineContext`, before `commands` to `Commands`, and
+//! inside State Node to the State context. Commands and their fields, and context fields accept attributes too.
+//! Contexts implement `Debug`, `Clone` and `PartialEq`, and `Commands` implements `Debug` and `PartialEq`,
+//! unless their attributes derive these traits. Derived `Clone` and `PartialEq` require all fields to implement
+//! them. Derive `PartialEq` together with `Hash`:
+//!
+//! ```
+//! #[macro_use] extern crate macro_machine;
+//!
+//! mod machines {
+//!     declare_machine!(
+//!         /// Worker exported from the module
+//!         pub Worker #[derive(Debug)] machine_context{jobs: u32} (Idle)
+//!         states[Idle, Busy]
+//!         #[derive(Clone)]
+//!         commands[Start{job: u32}, Stop]
+//!         (Idle:
+//!             Start(job) => Busy{job: *job};
+//!         )
+//!         ( #[derive(PartialEq, Eq, Hash)] // Attributes of the State context
+//!         Busy context{job: u32}:
+//!             >> {machine_context.jobs += 1;}
+//!             Stop => Idle;
+//!         )
+//!     );
+//! }
+//!
+//! # fn main() {
+//!     use machines::Worker;
+//!
+//!     let mut machine = Worker::new(0);
+//!     let start = Worker::Commands::Start{job: 7};
+//!     machine.execute(&start.clone()).unwrap();
+//!     assert_eq!(format!("{:?}", machine.inner_context()), "MachineContext { jobs: 1 }");
+//! # }
+//! ```
+//!
+//! Machine can be restored from its State and machine-scoped context by `Machine::from_snapshot`. The State
+//! was entered before the snapshot was taken, so its Enter callback is not executed again. Machine with
+//! regions is restored from States of all its regions. With `serde` feature, `Machine`, `States`, `Commands`,
+//! `MachineContext` and State contexts implement `Serialize` and `Deserialize` if their fields do.
+//! Deserialized machine keeps its history and doesn't execute Enter callbacks either. Serde is used through
+//! `macro_machine`, so it's not required in dependencies of machine's crate:
+//!
+//! ```toml
+//! [dependencies]
+//! macro_machine = { version = "0.2", features = ["serde"] }
+//! ```
+//!
+//! ```
+//! #[macro_use] extern crate macro_machine;
+//!
+//! declare_machine!(
+//!     Device session{restarts: u32} (Booting)
+//!     states[Booting, Online]
+//!     commands[Ready]
+//!     (Booting:
+//!         Ready => Online{sessions: 1};
+//!     )
+//!     (Online context{sessions: u32}:
+//!         >> {session.restarts += 1;}
+//!     )
+//! );
+//!
+//! # fn main() {
+//!     use Device::*;
+//!
+//!     let machine = Device::Machine::from_snapshot(States::Online{context: Online{sessions: 3}}, MachineContext{restarts: 0});
+//!     assert_eq!(machine.state_id(), StateId::Online);
+//!     assert_eq!(machine.inner_context().restarts, 0); // Enter callback of Online wasn't executed
+//! # }
+//! ```
+//!
+//! Observers are notified about every Command dispatched to machine, rejected Commands, left and entered
+//! States and transitions. Observer implements generated `Observer` trait, whose methods do nothing by
+//! default, and is registered by `Machine::add_observer`. Each region of machine has its own `Observer`
+//! trait, which is registered by `add_<Region>_observer`, e.g. `add_Link_observer`. Command which other region
+//! handled is not reported as rejected:
+//!
+//! ```
+//! #[macro_use] extern crate macro_machine;
+//!
+//! declare_machine!(
+//!     Lamp (Off)
+//!     states[Off, On]
+//!     commands[Toggle, Dim]
+//!     (Off:
+//!         Toggle => On;
+//!     )
+//!     (On:
+//!         Toggle => Off;
+//!     )
+//! );
+//!
+//! struct Audit(std::rc::Rc<std::cell::RefCell<Vec<String>>>);
+//!
+//! impl Lamp::Observer for Audit {
+//!     fn on_transition(&mut self, from: Lamp::StateId, command: &Lamp::Commands, to: Lamp::StateId) {
+//!         self.0.borrow_mut().push(format!("{} -{:?}-> {}", from, command, to));
+//!     }
+//!     fn on_reject(&mut self, state: Lamp::StateId, command: &Lamp::Commands, error: &Lamp::Error) {
+//!         self.0.borrow_mut().push(format!("{}: {}", state, error));
+//!     }
+//! }
+//!
+//! # fn main() {
+//!     let log = std::rc::Rc::new(std::cell::RefCell::new(Vec::new()));
+//!     let mut machine = Lamp::new();
+//!     machine.add_observer(Audit(log.clone()));
+//!     machine.execute(&Lamp::Commands::Toggle).unwrap();
+//!     assert!(machine.execute(&Lamp::Commands::Dim).is_err());
+//!     assert_eq!(*log.borrow(), vec!["Off -Toggle-> On", "On: command Dim is not handled in state On"]);
+//! # }
+//! ```
+//!
+//! Machine doesn't print anything. With `log` feature, it logs Commands dispatched to machine at trace
+//! level, transitions and rejected Commands at debug level. With `tracing` feature, it emits the same
+//! events with `machine`, `from`, `command`, `to`, `state` and `error` fields and enters `execute` span on
+//! each execution of Command. Events of region are given machine name `Machine::Region`:
+//!
+//! ```toml
+//! [dependencies]
+//! macro_machine = { version = "0.2", features = ["tracing"] }
+//! ```
+//!
+//! State can move machine to other State when machine stays in it too long: `after 5s => Idle;`. Timeout is
+//! integer with unit `ms`, `s`, `m` or `h` and can have a callback like Command reaction. State has at most one
+//! timeout and its child States inherit it. Timeout starts when the State is entered, transitions between its
+//! child States don't restart it. Machine doesn't spawn threads: `next_deadline()` tells when the earliest
+//! timeout expires and `poll(now)` fires it. Time of entering States is taken from `SystemClock` or from
+//! other `Clock` given to `with_clock`, so a fake clock can drive machine in tests. Observers are notified
+//! by `on_timeout`, `log` and `tracing` features report timeouts at debug level:
+//!
+//! ```
+//! #[macro_use] extern crate macro_machine;
+//!
+//! use std::time::{Duration, Instant};
+//!
+//! declare_machine!(
+//!     Door log{closed: u32} (Closed)
+//!     states[Closed, Open]
+//!     commands[Push]
+//!     (Closed:
+//!         Push => Open;
+//!     )
+//!     (Open:
+//!         after 30s {log.closed += 1;} => Closed;
+//!     )
+//! );
+//!
+//! struct Fixed(Instant);
+//!
+//! impl macro_machine::Clock for Fixed {
+//!     fn now(&self) -> Instant {
+//!         self.0
+//!     }
+//! }
+//!
+//! # fn main() {
+//!     let start = Instant::now();
+//!     let mut door = Door::new(0).with_clock(Fixed(start));
+//!     door.execute(&Door::Commands::Push).unwrap();
+//!     assert_eq!(door.next_deadline(), Some(start + Duration::from_secs(30)));
+//!     assert!(!door.poll(start + Duration::from_secs(10)).unwrap());
+//!     assert!(door.poll(start + Duration::from_secs(30)).unwrap());
+//!     assert_eq!(door.state_id(), Door::StateId::Closed);
+//!     assert_eq!(door.inner_context().closed, 1);
+//! # }
+//! ```
+//!
+//! Machine declared `async` executes its callbacks in async functions, so Enter, Leave, reaction, timeout and
+//! join callbacks can await. `new()`, `execute()` and `poll()` of async machine are async, guards and other
+//! methods are not. Machine uses only `core::future`, so any executor can run it. Its observers and clock must
+//! be `Send`, so futures of machine are `Send` if its contexts are. Callbacks are executed in the same order as
+//! in synchronous machine: old state is left before context of the new state is created. Callbacks can await
+//! only in crates of 2018 and later editions:
+//!
+//! ```edition2018
+//! use macro_machine::declare_machine;
+//!
+//! struct Socket {
+//!     sent: Vec<&'static str>,
+//! }
+//!
+//! impl Socket {
+//!     async fn send(&mut self, message: &'static str) {
+//!         self.sent.push(message);
+//!     }
+//! }
+//!
+//! declare_machine!(
+//!     pub async Client socket{inner: Socket} (Offline)
+//!     states[Offline, Online]
+//!     commands[Connect]
+//!     (Offline:
+//!         Connect {socket.inner.send("hello").await;} => Online;
+//!     )
+//!     (Online:
+//!         >> {socket.inner.send("ready").await;}
+//!     )
+//! );
+//! # fn block_on<F: std::future::Future>(future: F) -> F::Output {
+//! #     struct Noop;
+//! #     impl std::task::Wake for Noop {
+//! #         fn wake(self: std::sync::Arc<Self>) {}
+//! #     }
+//! #     let waker = std::task::Waker::from(std::sync::Arc::new(Noop));
+//! #     let mut context = std::task::Context::from_waker(&waker);
+//! #     let mut future = Box::pin(future);
+//! #     loop {
+//! #         if let std::task::Poll::Ready(output) = future.as_mut().poll(&mut context) {
+//! #             return output;
+//! #         }
+//! #     }
+//! # }
+//!
+//! # fn main() {
+//! block_on(async {
+//!     let mut client = Client::new(Socket{sent: Vec::new()}).await;
+//!     client.execute(&Client::Commands::Connect).await.unwrap();
+//!     assert_eq!(client.inner_context().inner.sent, vec!["hello", "ready"]);
+//! });
+//! # }
+//! ```
+//!
+//! Futures of `execute()` and `poll()` can be dropped before completion, e.g. by `select!` or a timeout. Current
+//! State stays in machine while callbacks run and they change its context through a reference, so machine is left
+//! in the old State if the future is dropped in Command Reaction or Leave callback, and in the new State if it's
+//! dropped in Enter callback. Changes made by callbacks so far are kept, the rest of callbacks is not executed
+//! and raised Commands are dropped.
+//!
+//! Callbacks can't execute Commands, because machine is borrowed while they run. They can raise them by
+//! `raise(Commands::...)` instead. Raised Commands are queued and executed by `execute()`, `poll()` or `new()`
+//! after the Command which raised them is completed, in order they were raised. Error of raised Command is
+//! returned by `execute()` or `try_new()` and drops the rest of the queue. Commands raising each other forever are stopped by
+//! `Error::Overflow` when more Commands are raised during execution of one Command than `queue(N)` allows,
+//! 32 by default:
+//!
+//! ```
+//! #[macro_use] extern crate macro_machine;
+//!
+//! declare_machine!(
+//!     Loader log{entries: Vec<&'static str>} (Idle)
+//!     states[Idle, Loading, Ready]
+//!     commands[Load, Loaded]
+//!     queue(8)
+//!     (Idle:
+//!         Load {raise(Commands::Loaded);} => Loading;
+//!     )
+//!     (Loading:
+//!         >> {log.entries.push("loading");}
+//!         Loaded => Ready;
+//!     )
+//!     (Ready:
+//!         >> {log.entries.push("ready");}
+//!     )
+//! );
+//!
+//! # fn main() {
+//!     let mut loader = Loader::new(Vec::new());
+//!     loader.execute(&Loader::Commands::Load).unwrap();
+//!     assert_eq!(loader.state_id(), Loader::StateId::Ready);
+//!     assert_eq!(loader.inner_context().entries, vec!["loading", "ready"]);
+//! # }
+//! ```
+//!
+//! Command which current state doesn't handle can be deferred by `defer Command;` in state node instead of
+//! being rejected. Deferred Commands are kept in pending buffer, `pending()` shows them in order of arrival.
+//! After each Command the oldest pending one which current state reacts on and doesn't defer is replayed.
+//! Replayed Command was sent before, so its error is not returned by `execute()`: its rejection is reported to
+//! observers and errors are logged with `log` and `tracing` features. Child states defer Commands of their
+//! parents too. Commands are cloned into the buffer, so they have to be `Clone`. When the buffer already holds
+//! `pending(N)` Commands, 32 by default, `Error::PendingFull` is returned and the Command is dropped. Observers
+//! are notified about Commands put into the buffer by `on_defer` and about dropped ones by `on_reject`. Machine
+//! with regions defers Command which no region handled if current state of any region defers it:
+//!
+//! ```
+//! #[macro_use] extern crate macro_machine;
+//!
+//! declare_machine!(
+//!     Uploader log{sent: Vec<u32>} (Idle)
+//!     states[Idle, Sending]
+//!     #[derive(Clone)]
+//!     commands[Upload{chunk: u32}, Uploaded]
+//!     pending(16)
+//!     (Idle:
+//!         Upload(chunk) {log.sent.push(*chunk);} => Sending;
+//!     )
+//!     (Sending:
+//!         defer Upload;
+//!         Uploaded => Idle;
+//!     )
+//! );
+//!
+//! # fn main() {
+//!     let mut uploader = Uploader::new(Vec::new());
+//!     uploader.execute(&Uploader::Commands::Upload{chunk: 1}).unwrap();
+//!     uploader.execute(&Uploader::Commands::Upload{chunk: 2}).unwrap();
+//!     assert_eq!(uploader.pending().len(), 1);
+//!     uploader.execute(&Uploader::Commands::Uploaded).unwrap();
+//!     assert_eq!(uploader.state_id(), Uploader::StateId::Sending);
+//!     assert_eq!(uploader.inner_context().sent, vec![1, 2]);
+//! # }
+//! ```
+//!
+
+extern crate macro_machine_macros;
+
+// Machine declaration is parsed, validated and generated by procedural macro. It's invoked through macro_rules!,
+// so machine can be declared with #[macro_use] too.
+#[doc(hidden)]
+pub use macro_machine_macros::declare_machine as __declare_machine;
+
+// Generated items derive Serialize and Deserialize through this re-export, so machine's crate doesn't have to
+// depend on serde.
+#[cfg(feature = "serde")]
+#[doc(hidden)]
+pub extern crate serde as __serde;
+
+#[cfg(feature = "log")]
+#[doc(hidden)]
+pub extern crate log as __log;
+
+#[cfg(feature = "tracing")]
+#[doc(hidden)]
+pub extern crate tracing as __tracing;
+
+#[macro_export]
+macro_rules! declare_machine {
+    ($($machine:tt)*) => (
+        $crate::__declare_machine!{[$crate] $($machine)*}
+    );
+}
+
+/// Source of time for state timeouts. Machine uses `SystemClock` unless other clock is given to `with_clock`.
+pub trait Clock {
+    fn now(&self) -> std::time::Instant;
+}
+
+/// Clock backed by `std::time::Instant::now()`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> std::time::Instant {
+        std::time::Instant::now()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    fn tes(x:i16) {
+        println!("x:{}",x);
+    }
+
+    declare_machine!(
+    Mach1 (New{x:0})
+
+    states[New,InConfig,Operational]
+    commands[Configure, ConfigureDone, Drop]
+
+    ( New context{x: i16}:
+        >> {println!("Enter {:?}", context)}
+        << {println!("Leave {:?}", context)}
+        Configure     {println!("In New with context val: {}", context.x);} =>     InConfig{x:context.x+1, y:0};
+        ConfigureDone => New{x:0};
+    )
+    ( InConfig context{x:i16; y:i16}:
+        >> {println!("Enter {:?}", context)}
+        << {println!("Leave {:?}", context)}
+        ConfigureDone {tes(context.x)}=> Operational;
+    )
+    ( Operational context:
+        >> {println!("Enter {:?}", context)}
+        << {println!("Leave {:?}", context)}
+        ConfigureDone =>;
+        Drop => New{x:0};
+    )
+    );
+
+    declare_machine!(
+    Mach2 (State1)
+
+    states[State1,State2,State3]
+    commands[ToState1, ToState2, ToState3]
+
+    ( State1 :
+        ToState2 => State2;
+    )
+    ( State2 :
+        ToState3 => State3;
+    )
+    ( State3 :
+        ToState1 => State1;
+    )
+    );
+
+    #[test]
+    fn test1() {
+        let mut m = Mach1::new();
+        m.execute(&Mach1::Commands::Configure).unwrap();
+        m.execute(&Mach1::Commands::ConfigureDone).unwrap();
+        m.execute(&Mach1::Commands::Drop).unwrap();
+        m.execute(&Mach1::Commands::Configure).unwrap();
+        m.execute(&Mach1::Commands::ConfigureDone).unwrap();
+        m.execute(&Mach1::Commands::ConfigureDone).unwrap();
+        m.execute(&Mach1::Commands::ConfigureDone).unwrap();
+    }
+
+    #[test]
+    #[should_panic]
+    fn test2() {
+        let mut m = Mach2::new();
+        m.execute(&Mach2::Commands::ToState2).unwrap();
+        m.execute(&Mach2::Commands::ToState3).unwrap();
+        m.execute(&Mach2::Commands::ToState1).unwrap();
+        m.execute(&Mach2::Commands::ToState3).unwrap();
+    }
+
+    declare_machine!(
+    Mach3 glob_cont{id:i16}(State1{counter:0})
+
+    states[State1,State2,State3]
+    commands[ToState1, ToState2, ToState3]
+
+    ( State1 cont{counter:i16}:
+        >>{println!("Mach {} enter {:?}", glob_cont.id, cont);}
+        <<{cont.counter+=1; println!("Mach {} leave {:?}", glob_cont.id, cont);}
+        ToState2 => State2{counter: cont.counter};
+    )
+    ( State2 cont{counter:i16}:
+        >>{println!("Mach {} enter {:?}", glob_cont.id, cont);}
+        <<{cont.counter+=1; println!("Mach {} leave {:?}", glob_cont.id, cont);}
+        ToState3 => State3{counter: cont.counter};
+    )
+    ( State3 cont{counter:i16}:
+        >>{println!("Mach {} enter {:?}", glob_cont.id, cont);}
+        <<{cont.counter+=1; println!("Mach {} leave {:?}", glob_cont.id, cont);}
+        ToState1 => State1{counter: cont.counter};
+    )
+    );
+
+    #[test]
+    fn test3() {
+        let mut m = Mach3::new(0);
+        let mut m1 = Mach3::new(1);
+        m1.execute(&Mach3::Commands::ToState2).unwrap();
+        m.execute(&Mach3::Commands::ToState2).unwrap();
+        m.execute(&Mach3::Commands::ToState3).unwrap();
+        m1.execute(&Mach3::Commands::ToState3).unwrap();
+    }
+
+    #[derive(Clone)]
+    pub struct InnerMachineContext {
+        id: i16,
+        name: String,
+        counter: i16
+    }
+
+    declare_machine!(
+        Mach4 inner{st: InnerMachineContext} (State1)
+        states[State1,State2,State3]
+        commands[ToState1, ToState2, ToState3]
+
+        ( State1 :
+            << {println!("id={} name={} counter={}", inner.st.id, inner.st.name, inner.st.counter);}
+            ToState2 {inner.st.counter+=1;}=> State2;
+        )
+        ( State2 :
+            << {println!("id={} name={} counter={}", inner.st.id, inner.st.name, inner.st.counter);}
+            ToState3 {inner.st.counter+=1;}=> State3;
+        )
+        ( State3 :
+            << {println!("id={} name={} counter={}", inner.st.id, inner.st.name, inner.st.counter);}
+            ToState1 {inner.st.counter+=1;}=> State1;
+        )
+    );
+    #[test]
+    fn test4() {
+        let mut m = Mach4::new(InnerMachineContext{id:0, name: String::from("Mach 0"), counter: 0});
+        let mut m1 = Mach4::new(InnerMachineContext{id:1, name: String::from("Mach 1"), counter: 0});
+        let mut m2 = Mach4::new(InnerMachineContext{id:2, name: String::from("Mach 2"), counter: 0});
+        m.execute(&Mach4::Commands::ToState2).unwrap();
+        m.execute(&Mach4::Commands::ToState3).unwrap();
+        m1.execute(&Mach4::Commands::ToState2).unwrap();
+        m.execute(&Mach4::Commands::ToState1).unwrap();
+        m1.execute(&Mach4::Commands::ToState3).unwrap();
+        m2.execute(&Mach4::Commands::ToState2).unwrap();
+        m.execute(&Mach4::Commands::ToState2).unwrap();
         m2.execute(&Mach4::Commands::ToState3).unwrap();
         m1.execute(&Mach4::Commands::ToState1).unwrap();
     }
+
+    declare_machine!(
+    Mach5 info{name: String} (New)
+
+    states[New,Configured]
+    commands[Configure{addr: u32, port: u16}, Rename{name: String}, Drop]
+
+    ( New context:
+        Configure(addr, port) => Configured{addr: *addr, port: *port};
+    )
+    ( Configured context{addr: u32; port: u16}:
+        Configure(port) {context.port = *port;} =>;
+        Rename(name) {info.name = name.clone();} =>;
+        Drop => New;
+    )
+    );
+
+    #[test]
+    fn test5() {
+        let mut m = Mach5::new(String::new());
+        assert!(m.execute(&Mach5::Commands::Rename{name: String::from("Mach 5")}).is_err());
+        m.execute(&Mach5::Commands::Configure{addr: 10, port: 80}).unwrap();
+        m.execute(&Mach5::Commands::Configure{addr: 20, port: 8080}).unwrap();
+        m.execute(&Mach5::Commands::Rename{name: String::from("Mach 5")}).unwrap();
+        match m.get_current_state() {
+            Mach5::States::Configured{context} => {
+                assert_eq!(context.addr, 10);
+                assert_eq!(context.port, 8080);
+            },
+            _ => panic!("Mach5 must be configured"),
+        }
+        assert_eq!(m.get_inner_context().name, "Mach 5");
+        m.execute(&Mach5::Commands::Drop).unwrap();
+    }
+
+    declare_machine!(
+    Mach6 (Counting{counter: 0})
+
+    states[Counting,Done]
+    commands[Next, Set{value: i16}]
+
+    ( Counting context{counter: i16}:
+        Next [context.counter >= 3] => Done;
+        Next {context.counter += 1;} =>;
+        Set(value) [*value >= 0] {context.counter = *value;} =>;
+    )
+    ( Done context:
+        Set(value) [*value == 0] => Counting{counter: 0};
+    )
+    );
+
+    #[test]
+    fn test6() {
+        let mut m = Mach6::new();
+        m.execute(&Mach6::Commands::Next).unwrap();
+        m.execute(&Mach6::Commands::Next).unwrap();
+        assert_eq!(m.get_current_state(), Mach6::States::Counting{context: Mach6::Counting{counter: 2}});
+        assert!(m.execute(&Mach6::Commands::Set{value: -1}).is_err());
+        m.execute(&Mach6::Commands::Set{value: 3}).unwrap();
+        m.execute(&Mach6::Commands::Next).unwrap();
+        assert_eq!(m.get_current_state(), Mach6::States::Done{context: Mach6::Done{}});
+        assert!(m.execute(&Mach6::Commands::Next).is_err());
+        assert!(m.execute(&Mach6::Commands::Set{value: 1}).is_err());
+        m.execute(&Mach6::Commands::Set{value: 0}).unwrap();
+        assert_eq!(m.get_current_state(), Mach6::States::Counting{context: Mach6::Counting{counter: 0}});
+    }
+
+    #[test]
+    fn test7() {
+        use std::error::Error;
+
+        let mut m = Mach6::new();
+        let err = m.execute(&Mach6::Commands::Set{value: -1}).unwrap_err();
+        assert!(matches!(err, Mach6::Error::NoGuard{state: Mach6::StateId::Counting, command: Mach6::CommandId::Set}));
+        assert_eq!(err.to_string(), "no guard passed for command Set in state Counting");
+        m.execute(&Mach6::Commands::Set{value: 3}).unwrap();
+        m.execute(&Mach6::Commands::Next).unwrap();
+        let err = m.execute(&Mach6::Commands::Next).unwrap_err();
+        assert!(matches!(err, Mach6::Error::Unhandled{state: Mach6::StateId::Done, command: Mach6::CommandId::Next}));
+        assert_eq!(err.to_string(), "command Next is not handled in state Done");
+        assert!(err.source().is_none());
+    }
+
+    declare_machine!(
+    Mach7 stats{opened: u8} (Idle{attempts: 0})
+
+    states[Idle,Open,Broken]
+    commands[Open, Fail, Close, Retry]
+    error(String => Broken)
+
+    ( Idle context{attempts: u8}:
+        << {context.attempts += 10; if context.attempts == 10 {return Err(String::from("not ready"));}}
+        Retry {context.attempts += 1;} =>;
+        Open {stats.opened += 1; raise(Commands::Close);} => Open{fail: false};
+        Fail => Open{fail: true};
+    )
+    ( Open context{fail: bool}:
+        >> {if context.fail {return Err(String::from("can't open"));}}
+        Close => Idle{attempts: 1};
+    )
+    ( Broken context:
+        << {return Err(String::from("broken"));}
+        Close => Idle{attempts: 1};
+    )
+    );
+
+    #[test]
+    fn test8() {
+        let mut m = Mach7::new(0);
+        match m.execute(&Mach7::Commands::Open) {
+            Err(Mach7::Error::Leave{state: Mach7::StateId::Idle, error}) => assert_eq!(error, "not ready"),
+            r => panic!("unexpected result {:?}", r),
+        }
+        // Changes made by the reaction and the failed Leave callback are kept, raised Commands are dropped
+        assert_eq!(m.get_current_state(), Mach7::States::Idle{context: Mach7::Idle{attempts: 10}});
+        assert_eq!(m.inner_context().opened, 1);
+        m.execute(&Mach7::Commands::Retry).unwrap();
+        m.execute(&Mach7::Commands::Open).unwrap();
+        assert_eq!(m.get_current_state(), Mach7::States::Idle{context: Mach7::Idle{attempts: 1}});
+        assert_eq!(m.inner_context().opened, 2);
+        match m.execute(&Mach7::Commands::Fail) {
+            Err(Mach7::Error::Enter{state: Mach7::StateId::Open, error}) => assert_eq!(error, "can't open"),
+            r => panic!("unexpected result {:?}", r),
+        }
+        assert_eq!(m.get_current_state(), Mach7::States::Broken{context: Mach7::Broken{}});
+        assert!(matches!(m.execute(&Mach7::Commands::Close), Err(Mach7::Error::Leave{state: Mach7::StateId::Broken, ..})));
+        assert_eq!(m.state_id(), Mach7::StateId::Broken);
+    }
+
+    #[derive(Debug)]
+    pub struct Connection {
+        peer: String
+    }
+
+    declare_machine!(
+    Mach8 info{peer: String, received: usize} (Idle)
+
+    states[Idle,Connecting,Connected]
+    commands[Connect{peer: String}, Data{bytes: Vec<u8>}, Established, Drop]
+
+    ( Idle context:
+        Connect(peer) => Connecting{peer: peer.clone(), buffer: Vec::new()};
+    )
+    ( Connecting context{peer: String; buffer: Vec<u8>}:
+        Data(bytes) {context.buffer.extend_from_slice(bytes);} =>;
+        Established => Connected{connection: Box::new(Connection{peer: context.peer}), buffer: context.buffer};
+    )
+    ( Connected context{connection: Box<Connection>; buffer: Vec<u8>}:
+        >> {info.peer = context.connection.peer.clone(); info.received = context.buffer.len();}
+        Drop => Idle;
+    )
+    );
+
+    #[test]
+    fn test9() {
+        let mut m = Mach8::new(String::new(), 0);
+        m.execute(&Mach8::Commands::Connect{peer: String::from("peer")}).unwrap();
+        m.execute(&Mach8::Commands::Data{bytes: vec![1, 2, 3]}).unwrap();
+        m.execute(&Mach8::Commands::Data{bytes: vec![4]}).unwrap();
+        m.execute(&Mach8::Commands::Established).unwrap();
+        let info = m.get_inner_context();
+        assert_eq!(info.peer, "peer");
+        assert_eq!(info.received, 4);
+        assert!(m.execute(&Mach8::Commands::Established).is_err());
+        m.execute(&Mach8::Commands::Drop).unwrap();
+    }
+
+    #[test]
+    fn test10() {
+        let mut m = Mach8::new(String::new(), 0);
+        m.execute(&Mach8::Commands::Connect{peer: String::from("peer")}).unwrap();
+        m.execute(&Mach8::Commands::Data{bytes: vec![1, 2]}).unwrap();
+        match *m.current_state() {
+            Mach8::States::Connecting{ref context} => assert_eq!(context.buffer, vec![1, 2]),
+            _ => panic!("Mach8 must be connecting"),
+        }
+        m.execute(&Mach8::Commands::Established).unwrap();
+        match *m.current_state() {
+            Mach8::States::Connected{ref context} => {
+                assert_eq!(context.connection.peer, "peer");
+                assert_eq!(context.buffer, vec![1, 2]);
+            },
+            _ => panic!("Mach8 must be connected"),
+        }
+        assert!(m.is_in(Mach8::StateId::Connected));
+        m.inner_context_mut().received = 0;
+        assert_eq!(m.inner_context().received, 0);
+        assert_eq!(m.inner_context().peer, "peer");
+    }
+
+    #[test]
+    fn test11() {
+        use std::collections::HashSet;
+
+        let mut m = Mach2::new();
+        assert!(m.is_in(Mach2::StateId::State1));
+        assert_eq!(m.current_state().id(), Mach2::StateId::State1);
+        m.execute(&Mach2::Commands::ToState2).unwrap();
+        assert_eq!(m.state_id(), Mach2::StateId::State2);
+        assert!(!m.is_in(Mach2::StateId::State1));
+        assert_eq!(m.state_id().to_string(), "State2");
+        assert!(Mach2::StateId::State1 < Mach2::StateId::State3);
+        let visited: HashSet<Mach2::StateId> = vec![Mach2::StateId::State1, m.state_id(), Mach2::StateId::State1].into_iter().collect();
+        assert_eq!(visited.len(), 2);
+    }
+
+    declare_machine!(
+    Mach9 log{entries: Vec<&'static str>} (New)
+
+    states[New, Connected, Idle: Connected, Busy: Connected, Sending: Busy]
+    commands[Connect, Send, Done, Drop, Ping, Reset]
+
+    ( New :
+        >> {log.entries.push("enter New");}
+        << {log.entries.push("leave New");}
+        Connect => Idle;
+    )
+    ( Connected :
+        >> {log.entries.push("enter Connected");}
+        << {log.entries.push("leave Connected");}
+        Drop => New;
+        Ping =>;
+    )
+    ( Idle :
+        >> {log.entries.push("enter Idle");}
+        << {log.entries.push("leave Idle");}
+        Send => Sending{retries: 0};
+    )
+    ( Busy :
+        >> {log.entries.push("enter Busy");}
+        << {log.entries.push("leave Busy");}
+        Done => Idle;
+    )
+    ( Sending context{retries: u8}:
+        >> {log.entries.push("enter Sending");}
+        << {log.entries.push("leave Sending");}
+        Send {context.retries += 1;} =>;
+        Drop [context.retries > 0] => Idle;
+        Reset [false] =>;
+    )
+    );
+
+    #[test]
+    fn test12() {
+        fn take_log(m: &mut Mach9::Machine) -> Vec<&'static str> {
+            m.inner_context_mut().entries.drain(..).collect()
+        }
+
+        let mut m = Mach9::new(Vec::new());
+        assert_eq!(take_log(&mut m), vec!["enter New"]);
+        m.execute(&Mach9::Commands::Connect).unwrap();
+        assert_eq!(take_log(&mut m), vec!["leave New", "enter Connected", "enter Idle"]);
+        m.execute(&Mach9::Commands::Ping).unwrap();
+        assert!(take_log(&mut m).is_empty());
+        assert_eq!(m.state_id(), Mach9::StateId::Idle);
+
+        m.execute(&Mach9::Commands::Send).unwrap();
+        assert_eq!(take_log(&mut m), vec!["leave Idle", "enter Busy", "enter Sending"]);
+        assert!(m.is_in(Mach9::StateId::Sending));
+        assert!(m.is_in(Mach9::StateId::Busy));
+        assert!(m.is_in(Mach9::StateId::Connected));
+        assert!(!m.is_in(Mach9::StateId::Idle));
+        assert_eq!(Mach9::StateId::Sending.parent(), Some(Mach9::StateId::Busy));
+
+        // Inherited from Busy
+        m.execute(&Mach9::Commands::Done).unwrap();
+        assert_eq!(take_log(&mut m), vec!["leave Sending", "leave Busy", "enter Idle"]);
+
+        // Overridden Drop with passing guard
+        m.execute(&Mach9::Commands::Send).unwrap();
+        m.execute(&Mach9::Commands::Send).unwrap();
+        take_log(&mut m);
+        m.execute(&Mach9::Commands::Drop).unwrap();
+        assert_eq!(take_log(&mut m), vec!["leave Sending", "leave Busy", "enter Idle"]);
+
+        // Overridden Drop with failed guard falls back to Drop of Connected
+        m.execute(&Mach9::Commands::Send).unwrap();
+        take_log(&mut m);
+        match m.execute(&Mach9::Commands::Reset) {
+            Err(Mach9::Error::NoGuard{state: Mach9::StateId::Sending, command: Mach9::CommandId::Reset}) => {},
+            r => panic!("unexpected result {:?}", r),
+        }
+        m.execute(&Mach9::Commands::Drop).unwrap();
+        assert_eq!(take_log(&mut m), vec!["leave Sending", "leave Busy", "leave Connected", "enter New"]);
+        match m.execute(&Mach9::Commands::Done) {
+            Err(Mach9::Error::Unhandled{state: Mach9::StateId::New, command: Mach9::CommandId::Done}) => {},
+            r => panic!("unexpected result {:?}", r),
+        }
+    }
+
+    declare_machine!(
+    Mach10 log{entries: Vec<String>, joined: u8}
+    commands[Connect, Login{user: &'static str}, Logout, Disconnect, Fail]
+    join {log.joined += 1;}
+
+    region Link (Down) {
+        states[Down, Up, Broken]
+        final[Down]
+        error(String => Broken)
+        ( Down:
+            Connect => Up;
+        )
+        ( Up:
+            >> {log.entries.push(String::from("link up"));}
+            Disconnect => Down;
+            Fail => Broken;
+        )
+        ( Broken:
+            >> {if !log.entries.is_empty() {return Err(String::from("broken"));}}
+        )
+    }
+    region Auth (Anonymous) {
+        states[Anonymous, Authorized]
+        final[Anonymous]
+        ( Anonymous:
+            Login(user) [!user.is_empty()] {log.entries.push(format!("login {}", user));} => Authorized{user: *user};
+        )
+        ( Authorized context{user: &'static str}:
+            Logout => Anonymous;
+            Disconnect => Anonymous;
+        )
+    }
+    );
+
+    #[test]
+    fn test13() {
+        use self::Mach10::{Link, Auth, RegionId};
+
+        let mut m = Mach10::new(Vec::new(), 0);
+        assert!(m.is_joined());
+        assert_eq!(m.execute(&Mach10::Commands::Connect).unwrap(), vec![RegionId::Link]);
+        assert_eq!(m.execute(&Mach10::Commands::Login{user: "admin"}).unwrap(), vec![RegionId::Auth]);
+        assert_eq!(m.Link().id(), Link::StateId::Up);
+        assert!(match *m.Auth() {
+            Auth::States::Authorized{ref context} => context.user == "admin",
+            _ => false
+        });
+        assert!(!m.is_joined());
+        assert_eq!(m.inner_context().entries, vec!["link up", "login admin"]);
+
+        match m.execute(&Mach10::Commands::Login{user: ""}) {
+            Err(Mach10::Error::Unhandled{command: Mach10::CommandId::Login}) => {},
+            r => panic!("unexpected result {:?}", r),
+        }
+
+        // Both regions handle Disconnect and reach their final states at once
+        assert_eq!(m.execute(&Mach10::Commands::Disconnect).unwrap(), vec![RegionId::Link, RegionId::Auth]);
+        assert!(m.is_joined());
+        assert_eq!(m.inner_context().joined, 1);
+
+        m.execute(&Mach10::Commands::Connect).unwrap();
+        match m.execute(&Mach10::Commands::Fail) {
+            Err(Mach10::Error::Failed{ref handled, ref errors}) if handled.is_empty() && errors.len() == 1 => {
+                assert!(matches!(errors[0], Mach10::Error::Link(Link::Error::Enter{state: Link::StateId::Broken, ..})));
+            },
+            r => panic!("unexpected result {:?}", r),
+        }
+        assert_eq!(m.Link().id(), Link::StateId::Broken);
+        assert_eq!(m.Auth().id(), Auth::StateId::Anonymous);
+        assert_eq!(m.inner_context().joined, 1);
+    }
+
+    declare_machine!(
+    Mach11 log{entries: Vec<String>} (Editing{text: String::new()})
+    states[Editing, Saved, Dialog, Confirm: Dialog, Help: Dialog]
+    commands[Type{ch: char}, Save, Ask, Help, Cancel, Close]
+    history(2)
+    ( Editing context{text: String}:
+        >> {log.entries.push(format!("enter Editing {}", context.text));}
+        Type(ch) {context.text.push(*ch);} =>;
+        Save => Saved{text: context.text};
+        Ask => Confirm;
+    )
+    ( Saved context{text: String}:
+        Type(ch) => Editing{text: format!("{}{}", context.text, ch)};
+    )
+    ( Dialog:
+        Cancel => back; // Inherited by Confirm and Help
+    )
+    ( Confirm:
+        Help => Help;
+        Close {log.entries.push(String::from("close"));} => back;
+    )
+    ( Help:
+        Close [log.entries.len() > 100] => back;
+    )
+    );
+
+    #[test]
+    fn test14() {
+        let mut m = Mach11::new(Vec::new());
+        assert!(m.history().is_empty());
+        m.execute(&Mach11::Commands::Type{ch: 'a'}).unwrap();
+        m.execute(&Mach11::Commands::Ask).unwrap();
+        assert_eq!(m.history().len(), 1);
+        // Confirm and Help are left, Editing is restored with its context
+        m.execute(&Mach11::Commands::Help).unwrap();
+        assert_eq!(m.history().back().map(Mach11::States::id), Some(Mach11::StateId::Confirm));
+        m.execute(&Mach11::Commands::Cancel).unwrap();
+        assert_eq!(m.state_id(), Mach11::StateId::Confirm);
+        m.execute(&Mach11::Commands::Close).unwrap();
+        assert_eq!(m.state_id(), Mach11::StateId::Editing);
+        assert!(m.history().is_empty());
+        assert_eq!(m.inner_context().entries, vec!["enter Editing ", "close", "enter Editing a"]);
+
+        // Only the last two states are kept
+        m.execute(&Mach11::Commands::Save).unwrap();
+        m.execute(&Mach11::Commands::Type{ch: 'b'}).unwrap();
+        m.execute(&Mach11::Commands::Ask).unwrap();
+        let history: Vec<_> = m.history().iter().map(Mach11::States::id).collect();
+        assert_eq!(history, vec![Mach11::StateId::Saved, Mach11::StateId::Editing]);
+        match m.execute(&Mach11::Commands::Help).and_then(|_| m.execute(&Mach11::Commands::Close)) {
+            Err(Mach11::Error::NoGuard{state: Mach11::StateId::Help, command: Mach11::CommandId::Close}) => {},
+            r => panic!("unexpected result {:?}", r),
+        }
+        let history: Vec<_> = m.history().iter().map(Mach11::States::id).collect();
+        assert_eq!(history, vec![Mach11::StateId::Editing, Mach11::StateId::Confirm]);
+        m.execute(&Mach11::Commands::Cancel).unwrap();
+        m.execute(&Mach11::Commands::Cancel).unwrap();
+        assert!(match *m.current_state() {
+            Mach11::States::Editing{ref context} => context.text == "ab",
+            _ => false
+        });
+        assert!(m.history().is_empty());
+    }
+
+    declare_machine!(
+    Mach12 (Paused)
+    states[Playing, Paused]
+    commands[Play, Pause, Resume]
+    history(1)
+    ( Playing:
+        Pause => Paused;
+    )
+    ( Paused:
+        Play => Playing;
+        Resume => back;
+    )
+    );
+
+    #[test]
+    fn test15() {
+        // Back is not possible until machine leaves a State
+        let mut m = Mach12::new();
+        assert!(m.history().is_empty());
+        match m.execute(&Mach12::Commands::Resume) {
+            Err(Mach12::Error::NoHistory{state: Mach12::StateId::Paused, command: Mach12::CommandId::Resume}) => {},
+            r => panic!("unexpected result {:?}", r),
+        }
+        m.execute(&Mach12::Commands::Play).unwrap();
+        m.execute(&Mach12::Commands::Pause).unwrap();
+        m.execute(&Mach12::Commands::Resume).unwrap();
+        assert_eq!(m.state_id(), Mach12::StateId::Playing);
+    }
+
+    #[test]
+    fn test16() {
+        assert_eq!(Mach2::Machine::to_dot(), concat!(
+            "digraph Mach2 {\n",
+            "    \"__start\" [shape=point];\n",
+            "    \"State1\" [label=\"State1\"];\n",
+            "    \"State2\" [label=\"State2\"];\n",
+            "    \"State3\" [label=\"State3\"];\n",
+            "    \"__start\" -> \"State1\";\n",
+            "    \"State1\" -> \"State2\" [label=\"ToState2\"];\n",
+            "    \"State2\" -> \"State3\" [label=\"ToState3\"];\n",
+            "    \"State3\" -> \"State1\" [label=\"ToState1\"];\n",
+            "}\n"));
+
+        let dot = Mach11::Machine::to_dot();
+        assert!(dot.contains("    subgraph \"cluster_Dialog\" {\n        label=\"Dialog\";\n        \"Dialog\" [label=\"Dialog\"];\n        \"Confirm\" [label=\"Confirm\"];\n"));
+        assert!(dot.contains("\"Editing\" -> \"Editing\" [label=\"Type\"];"));
+        assert!(dot.contains("\"Dialog\" -> \"__history\" [label=\"Cancel\"];"));
+        assert!(dot.contains("\"Help\" -> \"__history\" [label=\"Close [log.entries.len() > 100]\"];"));
+
+        let dot = Mach10::Machine::to_dot();
+        assert!(dot.contains("    subgraph cluster_Auth {\n        label=\"Auth\";\n"));
+        assert!(dot.contains("\"Link::__start\" -> \"Link::Down\";"));
+        // Spacing of guard text is chosen by stringify!, it differs between compilers
+        let guard = Mach10::Auth::TRANSITIONS[0].guard.unwrap();
+        assert!(guard.contains("user.is_empty()"));
+        assert!(dot.contains(&format!("\"Auth::Anonymous\" -> \"Auth::Authorized\" [label=\"Login [{}]\"];", guard)));
+    }
+
+    #[test]
+    fn test17() {
+        assert_eq!(Mach1::Machine::to_plantuml(), concat!(
+            "@startuml\n",
+            "state New\n",
+            "state InConfig\n",
+            "state Operational\n",
+            "[*] --> New\n",
+            "New : entry /\n",
+            "New : exit /\n",
+            "InConfig : entry /\n",
+            "InConfig : exit /\n",
+            "Operational : entry /\n",
+            "Operational : exit /\n",
+            "New --> InConfig : Configure\n",
+            "New --> New : ConfigureDone\n",
+            "InConfig --> Operational : ConfigureDone\n",
+            "Operational : ConfigureDone / internal\n",
+            "Operational --> New : Drop\n",
+            "@enduml\n"));
+
+        assert_eq!(Mach11::Machine::to_mermaid(), concat!(
+            "stateDiagram-v2\n",
+            "    state Editing\n",
+            "    state Saved\n",
+            "    state Dialog {\n",
+            "        state Confirm\n",
+            "        state Help\n",
+            "    }\n",
+            "    state \"H\" as __history\n",
+            "    [*] --> Editing\n",
+            "    Editing : entry /\n",
+            "    Editing : Type / internal\n",
+            "    Editing --> Saved : Save\n",
+            "    Editing --> Confirm : Ask\n",
+            "    Saved --> Editing : Type\n",
+            "    Dialog --> __history : Cancel\n",
+            "    Confirm --> Help : Help\n",
+            "    Confirm --> __history : Close\n",
+            "    Help --> __history : Close [log.entries.len() > 100]\n"));
+        assert!(Mach11::Machine::to_plantuml().contains("Confirm --> [H] : Close\n"));
+
+        let uml = Mach10::Machine::to_plantuml();
+        assert!(uml.starts_with("@startuml\nstate Mach10 {\n    state \"Down\" as Link_Down\n"));
+        assert!(uml.contains("    Link_Up --> Link_Broken : Fail\n    --\n    state \"Anonymous\" as Auth_Anonymous\n"));
+        assert!(uml.ends_with("}\n@enduml\n"));
+        assert!(Mach10::Machine::to_mermaid().contains("        [*] --> Auth_Anonymous\n"));
+    }
+
+    #[test]
+    fn test18() {
+        use self::Mach11::{StateId, CommandId, Target, Transition};
+
+        assert_eq!(Mach11::STATES, &[StateId::Editing, StateId::Saved, StateId::Dialog, StateId::Confirm, StateId::Help]);
+        assert_eq!(Mach11::COMMANDS.len(), 6);
+        assert_eq!(Mach11::TRANSITIONS[0], Transition{from: StateId::Editing, command: CommandId::Type, guard: None, to: Target::Stay});
+        assert_eq!(Mach11::TRANSITIONS[1].to, Target::State(StateId::Saved));
+        let back: Vec<_> = Mach11::TRANSITIONS.iter().filter(|t| t.to == Target::Back).map(|t| t.from).collect();
+        assert_eq!(back, vec![StateId::Dialog, StateId::Confirm, StateId::Help]);
+        assert_eq!(Mach11::TRANSITIONS.last().unwrap().guard, Some("log.entries.len() > 100"));
+
+        // Every state reacts at least on one command
+        for state in Mach11::STATES {
+            assert!(!state.available_commands().is_empty());
+        }
+
+        let mut m = Mach11::new(Vec::new());
+        assert_eq!(m.available_commands(), vec![CommandId::Type, CommandId::Save, CommandId::Ask]);
+        m.execute(&Mach11::Commands::Ask).unwrap();
+        // Cancel is inherited from Dialog
+        assert_eq!(m.available_commands(), vec![CommandId::Help, CommandId::Cancel, CommandId::Close]);
+
+        let mut m = Mach10::new(Vec::new(), 0);
+        assert_eq!(m.available_commands(), vec![Mach10::CommandId::Connect, Mach10::CommandId::Login]);
+        m.execute(&Mach10::Commands::Connect).unwrap();
+        assert_eq!(m.available_commands(), vec![Mach10::CommandId::Login, Mach10::CommandId::Disconnect, Mach10::CommandId::Fail]);
+        assert_eq!(Mach10::Link::STATES.len(), 3);
+    }
+
+    declare_machine!(
+    Mach13 (Idle)
+    states[Idle, Running, Stuck, Orphan, Broken, Group, Member: Group, Failed]
+    commands[Start, Stop, Jam, Unused]
+    error(() => Failed)
+    ( Idle:
+        Start => Running;
+        Stop =>;
+    )
+    ( Running:
+        Stop => Idle;
+        Jam => Stuck;
+    )
+    ( Stuck:
+        Stop =>;
+        Jam => Stuck;
+    )
+    ( Orphan:
+        Start => Broken;
+    )
+    ( Broken:
+        Stop => Idle;
+    )
+    ( Group:
+        Stop => Idle;
+    )
+    ( Member:
+    )
+    ( Failed:
+        Start => Member;
+    )
+    );
+
+    #[test]
+    fn test19() {
+        use self::Mach13::StateId;
+
+        let analysis = Mach13::Machine::analyze();
+        // Broken is reachable only from unreachable Orphan. Group is reached through Member.
+        assert_eq!(analysis.unreachable, vec![StateId::Orphan, StateId::Broken]);
+        // Member inherits Stop from Group
+        assert_eq!(analysis.deadlocks, vec![StateId::Stuck]);
+        assert_eq!(analysis.unused_commands, vec![Mach13::CommandId::Unused]);
+        assert!(!analysis.is_ok());
+
+        assert!(Mach11::Machine::analyze().is_ok());
+
+        // Down of Link region is final, Broken is a deadlock
+        let analysis = Mach10::Machine::analyze();
+        assert_eq!(analysis.Link.deadlocks, vec![Mach10::Link::StateId::Broken]);
+        assert!(analysis.Auth.deadlocks.is_empty());
+        // Login is handled by Auth region only, so Link doesn't report it
+        assert!(analysis.Link.unused_commands.is_empty());
+        assert!(analysis.Auth.unused_commands.is_empty());
+        assert!(analysis.unused_commands.is_empty());
+    }
+
+    mod machines {
+        declare_machine!(
+        /// Machine exported from private module
+        #[cfg(test)]
+        pub(crate) Mach14 #[derive(Debug, Clone)] machine_context{#[allow(dead_code)] visits: u32} (Idle)
+        states[Idle, Busy]
+        #[derive(Clone, Debug)]
+        commands[Start{#[allow(dead_code)] job: u32}, Stop]
+        (#[derive(Clone)]
+        Idle:
+            Start => Busy{job: 1};
+        )
+        ( #[derive(Clone, PartialEq, Eq, Hash)]
+        Busy context{job: u32}:
+            >> {machine_context.visits += 1;}
+            Stop => Idle;
+        )
+        );
+    }
+
+    #[test]
+    fn test20() {
+        use std::collections::HashSet;
+        use self::machines::Mach14;
+
+        let command = Mach14::Commands::Start{job: 7};
+        let mut m = Mach14::new(0);
+        m.execute(&command.clone()).unwrap();
+        assert_eq!(format!("{:?}", m.inner_context()), "MachineContext { visits: 1 }");
+        let mut contexts = HashSet::new();
+        if let Mach14::States::Busy{ref context} = *m.current_state() {
+            contexts.insert(context.clone());
+        }
+        assert!(contexts.contains(&Mach14::Busy{job: 1}));
+    }
+
+    declare_machine!(
+    Mach15 counters{enters: u32} (Off)
+    states[Off, On]
+    commands[Toggle, Set{level: u8}]
+    history(2)
+    ( Off:
+        Toggle => On{level: 1};
+    )
+    ( On context{level: u8}:
+        >> {counters.enters += 1;}
+        Set(level) {context.level = *level;} =>;
+        Toggle => Off;
+    )
+    );
+
+    #[test]
+    fn test21() {
+        use self::Mach15::{States, StateId, MachineContext};
+
+        // Restored state was entered before the snapshot, so its Enter callback is not executed
+        let mut m = Mach15::Machine::from_snapshot(States::On{context: Mach15::On{level: 3}}, MachineContext{enters: 0});
+        assert_eq!(m.state_id(), StateId::On);
+        assert_eq!(m.inner_context().enters, 0);
+        assert!(m.history().is_empty());
+        m.execute(&Mach15::Commands::Toggle).unwrap();
+        m.execute(&Mach15::Commands::Toggle).unwrap();
+        assert_eq!(m.inner_context().enters, 1);
+
+        let m = Mach10::Machine::from_snapshot(Mach10::Link::States::Up{context: Mach10::Link::Up{}},
+            Mach10::Auth::States::Authorized{context: Mach10::Auth::Authorized{user: "admin"}},
+            Mach10::MachineContext{entries: Vec::new(), joined: 0});
+        assert_eq!(m.Link().id(), Mach10::Link::StateId::Up);
+        assert_eq!(m.Auth().id(), Mach10::Auth::StateId::Authorized);
+        assert!(m.inner_context().entries.is_empty());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test22() {
+        extern crate serde_json;
+        use self::Mach15::{States, StateId};
+
+        let mut m = Mach15::new(0);
+        m.execute(&Mach15::Commands::Toggle).unwrap();
+        m.execute(&Mach15::Commands::Set{level: 5}).unwrap();
+        m.execute(&Mach15::Commands::Toggle).unwrap();
+        m.execute(&Mach15::Commands::Toggle).unwrap();
+
+        // Machine keeps its state, history and machine-scoped context. Enter callback is not executed on restore.
+        let json = serde_json::to_string(&m).unwrap();
+        let mut restored: Mach15::Machine = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored.current_state(), m.current_state());
+        assert_eq!(restored.history(), m.history());
+        assert_eq!(restored.inner_context().enters, 2);
+        restored.execute(&Mach15::Commands::Toggle).unwrap();
+        assert_eq!(restored.state_id(), StateId::Off);
+
+        let state: States = serde_json::from_str(&serde_json::to_string(&States::On{context: Mach15::On{level: 7}}).unwrap()).unwrap();
+        let context: Mach15::MachineContext = serde_json::from_str("{\"enters\":4}").unwrap();
+        let m = Mach15::Machine::from_snapshot(state, context);
+        assert!(match *m.current_state() {
+            States::On{ref context} => context.level == 7,
+            _ => false
+        });
+        assert_eq!(m.inner_context().enters, 4);
+
+        let command: Mach15::Commands = serde_json::from_str(&serde_json::to_string(&Mach15::Commands::Set{level: 9}).unwrap()).unwrap();
+        assert_eq!(command, Mach15::Commands::Set{level: 9});
+
+        // Machine with regions is serialized with its regions. Login can't be deserialized from borrowed &'static str
+        let mut m = Mach10::new(Vec::new(), 0);
+        m.execute(&Mach10::Commands::Connect).unwrap();
+        let json = serde_json::to_string(&m).unwrap();
+        assert!(json.contains("\"Up\""));
+    }
+
+    struct Recorder(::std::rc::Rc<::std::cell::RefCell<Vec<String>>>);
+
+    impl Mach11::Observer for Recorder {
+        fn on_dispatch(&mut self, state: Mach11::StateId, command: &Mach11::Commands) {
+            self.0.borrow_mut().push(format!("dispatch {} {:?}", state, command));
+        }
+        fn on_reject(&mut self, state: Mach11::StateId, command: &Mach11::Commands, error: &Mach11::Error) {
+            self.0.borrow_mut().push(format!("reject {} {:?}: {}", state, command, error));
+        }
+        fn on_leave(&mut self, state: Mach11::StateId) {
+            self.0.borrow_mut().push(format!("leave {}", state));
+        }
+        fn on_transition(&mut self, from: Mach11::StateId, command: &Mach11::Commands, to: Mach11::StateId) {
+            self.0.borrow_mut().push(format!("transition {} {:?} {}", from, command, to));
+        }
+        fn on_enter(&mut self, state: Mach11::StateId) {
+            self.0.borrow_mut().push(format!("enter {}", state));
+        }
+    }
+
+    struct Counter(::std::rc::Rc<::std::cell::Cell<u32>>);
+
+    // Only transitions of Link and rejections of Auth are counted
+    impl Mach10::Link::Observer for Counter {
+        fn on_transition(&mut self, _: Mach10::Link::StateId, _: &Mach10::Commands, _: Mach10::Link::StateId) {
+            self.0.set(self.0.get() + 1);
+        }
+    }
+
+    impl Mach10::Auth::Observer for Counter {
+        fn on_reject(&mut self, _: Mach10::Auth::StateId, _: &Mach10::Commands, _: &Mach10::Auth::Error) {
+            self.0.set(self.0.get() + 1);
+        }
+    }
+
+    #[test]
+    fn test23() {
+        let events = ::std::rc::Rc::new(::std::cell::RefCell::new(Vec::new()));
+        let mut m = Mach11::new(Vec::new());
+        m.add_observer(Recorder(events.clone()));
+        m.execute(&Mach11::Commands::Type{ch: 'a'}).unwrap();
+        m.execute(&Mach11::Commands::Ask).unwrap();
+        m.execute(&Mach11::Commands::Help).unwrap();
+        assert!(m.execute(&Mach11::Commands::Close).is_err());
+        assert!(m.execute(&Mach11::Commands::Save).is_err());
+        m.execute(&Mach11::Commands::Cancel).unwrap();
+        assert_eq!(*events.borrow(), vec![
+            "dispatch Editing Type { ch: 'a' }",
+            "dispatch Editing Ask",
+            "leave Editing",
+            "transition Editing Ask Confirm",
+            "enter Dialog",
+            "enter Confirm",
+            "dispatch Confirm Help",
+            "leave Confirm",
+            "transition Confirm Help Help",
+            "enter Help",
+            "dispatch Help Close",
+            "reject Help Close: no guard passed for command Close in state Help",
+            "dispatch Help Save",
+            "reject Help Save: command Save is not handled in state Help",
+            "dispatch Help Cancel",
+            "leave Help",
+            "transition Help Cancel Confirm",
+            "enter Confirm",
+        ]);
+
+        let transitions = ::std::rc::Rc::new(::std::cell::Cell::new(0));
+        let mut m = Mach10::new(Vec::new(), 0);
+        let rejections = ::std::rc::Rc::new(::std::cell::Cell::new(0));
+        m.add_Link_observer(Counter(transitions.clone()));
+        m.add_Auth_observer(Counter(rejections.clone()));
+        m.execute(&Mach10::Commands::Connect).unwrap();
+        m.execute(&Mach10::Commands::Login{user: "admin"}).unwrap();
+        m.execute(&Mach10::Commands::Disconnect).unwrap();
+        assert_eq!(transitions.get(), 2);
+        // Command handled by other region is not rejected
+        assert_eq!(rejections.get(), 0);
+        assert!(m.execute(&Mach10::Commands::Logout).is_err());
+        assert_eq!(rejections.get(), 1);
+    }
+
+    declare_machine!(
+    Mach16 (Idle)
+    states[Idle, Busy]
+    commands[Go, Halt]
+    ( Idle:
+        Go => Busy;
+    )
+    ( Busy:
+        Halt => Idle;
+    )
+    );
+
+    #[cfg(feature = "log")]
+    #[test]
+    fn test24() {
+        use std::sync::Mutex;
+        use crate::__log::{Log, Metadata, Record, LevelFilter};
+
+        static RECORDS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+        struct Logger;
+        impl Log for Logger {
+            fn enabled(&self, _: &Metadata) -> bool {
+                true
+            }
+            // Other tests are executed concurrently, so only records of Mach16 are kept
+            fn log(&self, record: &Record) {
+                let message = record.args().to_string();
+                if message.starts_with("Mach16:") {
+                    RECORDS.lock().unwrap().push(format!("{} {}", record.level(), message));
+                }
+            }
+            fn flush(&self) {}
+        }
+        __log::set_logger(&Logger).unwrap();
+        __log::set_max_level(LevelFilter::Trace);
+
+        let mut m = Mach16::new();
+        m.execute(&Mach16::Commands::Go).unwrap();
+        assert!(m.execute(&Mach16::Commands::Go).is_err());
+        assert_eq!(*RECORDS.lock().unwrap(), vec![
+            "TRACE Mach16: Go dispatched in Idle",
+            "DEBUG Mach16: Idle --Go--> Busy",
+            "TRACE Mach16: Go dispatched in Busy",
+            "DEBUG Mach16: Go rejected in Busy: command Go is not handled in state Busy",
+        ]);
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn test25() {
+        use std::fmt::Write;
+        use std::sync::{Arc, Mutex};
+        use crate::__tracing::{Event, Metadata, Subscriber};
+        use crate::__tracing::field::{Field, Visit};
+        use crate::__tracing::span::{Attributes, Id, Record};
+
+        struct Fields(String);
+        impl Visit for Fields {
+            fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+                write!(self.0, " {}={:?}", field.name(), value).unwrap();
+            }
+        }
+        struct Recorder(Arc<Mutex<Vec<String>>>);
+        impl Subscriber for Recorder {
+            fn enabled(&self, _: &Metadata) -> bool {
+                true
+            }
+            fn new_span(&self, span: &Attributes) -> Id {
+                let mut fields = Fields(format!("span {}", span.metadata().name()));
+                span.record(&mut fields);
+                self.0.lock().unwrap().push(fields.0);
+                Id::from_u64(1)
+            }
+            fn record(&self, _: &Id, _: &Record) {}
+            fn record_follows_from(&self, _: &Id, _: &Id) {}
+            fn event(&self, event: &Event) {
+                let mut fields = Fields(event.metadata().level().to_string());
+                event.record(&mut fields);
+                self.0.lock().unwrap().push(fields.0);
+            }
+            fn enter(&self, _: &Id) {
+                self.0.lock().unwrap().push(String::from("enter"));
+            }
+            fn exit(&self, _: &Id) {
+                self.0.lock().unwrap().push(String::from("exit"));
+            }
+        }
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+        __tracing::subscriber::with_default(Recorder(events.clone()), || {
+            let mut m = Mach16::new();
+            m.execute(&Mach16::Commands::Go).unwrap();
+            assert!(m.execute(&Mach16::Commands::Go).is_err());
+        });
+        assert_eq!(*events.lock().unwrap(), vec![
+            "span execute machine=\"Mach16\" command=Go",
+            "enter",
+            "TRACE message=dispatch machine=\"Mach16\" state=Idle command=Go",
+            "DEBUG message=transition machine=\"Mach16\" from=Idle command=Go to=Busy",
+            "exit",
+            "span execute machine=\"Mach16\" command=Go",
+            "enter",
+            "TRACE message=dispatch machine=\"Mach16\" state=Busy command=Go",
+            "DEBUG message=rejected machine=\"Mach16\" state=Busy command=Go error=command Go is not handled in state Busy",
+            "exit",
+        ]);
+    }
+
+    declare_machine!(
+    Mach17 log{entries: Vec<&'static str>} (Idle)
+    states[Idle, Active, Working: Active, Waiting: Active]
+    commands[Start, Wait]
+
+    ( Idle :
+        Start => Working;
+    )
+    ( Active :
+        after 10s {log.entries.push("expired");} => Idle;
+    )
+    ( Working :
+        >> {log.entries.push("enter Working");}
+        Wait => Waiting;
+        after 500ms => Waiting;
+    )
+    ( Waiting :
+        Start => Working;
+    )
+    );
+
+    declare_machine!(
+    Mach18
+    commands[Go]
+
+    region Door (Closed) {
+        states[Closed, Open]
+        final[Closed]
+        ( Closed: Go => Open; )
+        ( Open: after 1m => Closed; )
+    }
+    region Light (Off) {
+        states[Off, On]
+        final[Off]
+        ( Off: Go => On; )
+        ( On: after 30s => Off; )
+    }
+    );
+
+    #[test]
+    fn test26() {
+        use std::cell::{Cell, RefCell};
+        use std::rc::Rc;
+        use std::time::{Duration, Instant};
+        use self::Mach17::StateId;
+        use crate::Clock;
+
+        struct Fake(Rc<Cell<Instant>>);
+        impl Clock for Fake {
+            fn now(&self) -> Instant {
+                self.0.get()
+            }
+        }
+        struct Recorder(Rc<RefCell<Vec<(StateId, StateId)>>>);
+        impl Mach17::Observer for Recorder {
+            fn on_timeout(&mut self, from: StateId, to: StateId) {
+                self.0.borrow_mut().push((from, to));
+            }
+        }
+
+        let start = Instant::now();
+        let time = Rc::new(Cell::new(start));
+        let timeouts = Rc::new(RefCell::new(Vec::new()));
+        let mut m = Mach17::new(Vec::new()).with_clock(Fake(time.clone()));
+        m.add_observer(Recorder(timeouts.clone()));
+        assert_eq!(m.next_deadline(), None);
+        m.execute(&Mach17::Commands::Start).unwrap();
+        assert_eq!(m.next_deadline(), Some(start + Duration::from_millis(500)));
+        assert!(!m.poll(start + Duration::from_millis(499)).unwrap());
+        assert!(m.poll(start + Duration::from_millis(500)).unwrap());
+        assert_eq!(m.state_id(), StateId::Waiting);
+        // Active is not left, so its timeout is not restarted.
+        assert_eq!(m.next_deadline(), Some(start + Duration::from_secs(10)));
+        time.set(start + Duration::from_secs(5));
+        m.execute(&Mach17::Commands::Start).unwrap();
+        // Both timeouts expired, the one of the innermost state is fired first.
+        assert!(m.poll(start + Duration::from_secs(10)).unwrap());
+        assert_eq!(m.state_id(), StateId::Waiting);
+        assert!(m.poll(start + Duration::from_secs(10)).unwrap());
+        assert_eq!(m.state_id(), StateId::Idle);
+        assert_eq!(m.inner_context().entries, vec!["enter Working", "enter Working", "expired"]);
+        assert_eq!(*timeouts.borrow(), vec![
+            (StateId::Working, StateId::Waiting),
+            (StateId::Working, StateId::Waiting),
+            (StateId::Waiting, StateId::Idle),
+        ]);
+        assert_eq!(StateId::Working.timeout(), Some(Duration::from_millis(500)));
+        assert_eq!(Mach17::TIMEOUTS.len(), 2);
+        assert!(Mach17::Machine::analyze().is_ok());
+        assert!(Mach17::Machine::to_mermaid().contains("Active --> Idle : after 10s"));
+
+        // Timers of restored machine are started by the first poll.
+        let mut m = Mach17::Machine::from_snapshot(Mach17::States::Waiting{context: Mach17::Waiting{}}, Mach17::MachineContext{entries: Vec::new()});
+        assert_eq!(m.next_deadline(), None);
+        assert!(!m.poll(start).unwrap());
+        assert_eq!(m.next_deadline(), Some(start + Duration::from_secs(10)));
+
+        let mut m = Mach18::new().with_clock(Fake(time.clone()));
+        let now = time.get();
+        m.execute(&Mach18::Commands::Go).unwrap();
+        assert_eq!(m.next_deadline(), Some(now + Duration::from_secs(30)));
+        assert_eq!(m.poll(now + Duration::from_secs(30)).unwrap(), vec![Mach18::RegionId::Light]);
+        assert_eq!(m.poll(now + Duration::from_secs(60)).unwrap(), vec![Mach18::RegionId::Door]);
+        assert!(m.is_joined());
+        assert_eq!(m.next_deadline(), None);
+    }
+
+    declare_machine!(
+    async Mach19 log{entries: Vec<String>} (Down)
+    states[Down, Online, Up: Online]
+    commands[Connect, Drop]
+
+    ( Down :
+        << {log.entries.push(String::from("leave Down"));}
+        Connect {log.entries.push(String::from("connect"));} => Up{seen: log.entries.len()};
+    )
+    ( Online :
+        >> {log.entries.push(String::from("enter Online"));}
+        Drop => Down;
+        after 1s => Down;
+    )
+    ( Up context{seen: usize}:
+        >> {log.entries.push(format!("enter Up {}", context.seen));}
+    )
+    );
+
+    declare_machine!(
+    async Mach20 log{joined: u8}
+    commands[Go]
+    join {log.joined += 1;}
+
+    region Left (Idle) {
+        states[Idle, Done]
+        final[Done]
+        ( Idle: Go => Done; )
+        ( Done: )
+    }
+    );
+
+    struct Noop;
+    impl ::std::task::Wake for Noop {
+        fn wake(self: ::std::sync::Arc<Self>) {}
+    }
+
+    // Polls future once. Returns None if it's not ready yet.
+    fn poll_once<F: ::std::future::Future>(future: ::std::pin::Pin<&mut F>) -> Option<F::Output> {
+        let waker = ::std::task::Waker::from(::std::sync::Arc::new(Noop));
+        let mut context = ::std::task::Context::from_waker(&waker);
+        match future.poll(&mut context) {
+            ::std::task::Poll::Ready(output) => Some(output),
+            ::std::task::Poll::Pending => None,
+        }
+    }
+
+    fn block_on<F: ::std::future::Future>(future: F) -> F::Output {
+        let mut future = Box::pin(future);
+        loop {
+            if let Some(output) = poll_once(future.as_mut()) {
+                return output;
+            }
+        }
+    }
+
+    #[test]
+    fn test27() {
+        use std::time::Duration;
+
+        fn assert_send<T: Send>(value: T) -> T {
+            value
+        }
+
+        let mut m = block_on(assert_send(Mach19::new(Vec::new())));
+        block_on(assert_send(m.execute(&Mach19::Commands::Connect))).unwrap();
+        assert_eq!(m.state_id(), Mach19::StateId::Up);
+        // Old state is left before context of the new one is created.
+        assert_eq!(m.inner_context().entries, vec!["connect", "leave Down", "enter Online", "enter Up 2"]);
+        assert!(block_on(m.execute(&Mach19::Commands::Connect)).is_err());
+        let deadline = m.next_deadline().unwrap();
+        assert!(!block_on(m.poll(deadline - Duration::from_millis(1))).unwrap());
+        assert!(block_on(m.poll(deadline)).unwrap());
+        assert_eq!(m.state_id(), Mach19::StateId::Down);
+        assert_eq!(m.next_deadline(), None);
+
+        let mut m = block_on(Mach20::new(0));
+        assert_eq!(block_on(m.execute(&Mach20::Commands::Go)).unwrap(), vec![Mach20::RegionId::Left]);
+        assert_eq!(m.inner_context().joined, 1);
+    }
+
+    declare_machine!(
+    Mach21 log{entries: Vec<&'static str>} (Idle)
+    states[Idle, Loading, Ready, Ping, Pong]
+    commands[Load, Loaded, Check, Bounce]
+    queue(3)
+
+    ( Idle :
+        Load {raise(Commands::Loaded); log.entries.push("load");} => Loading;
+        Bounce => Ping;
+    )
+    ( Loading :
+        >> {log.entries.push("enter Loading"); raise(Commands::Check);}
+        Loaded => Ready;
+    )
+    ( Ready :
+        >> {log.entries.push("enter Ready");}
+        Check {log.entries.push("check");} =>;
+    )
+    ( Ping :
+        >> {raise(Commands::Bounce);}
+        Bounce => Pong;
+    )
+    ( Pong :
+        >> {raise(Commands::Bounce);}
+        Bounce => Ping;
+    )
+    );
+
+    declare_machine!(
+    Mach22
+    commands[Start, Stop]
+
+    region Motor (Off) {
+        states[Off, On]
+        ( Off: Start {raise(Commands::Stop);} => On; )
+        ( On: Stop => Off; )
+    }
+    region Light (Dark) {
+        states[Dark, Lit]
+        ( Dark: Start => Lit; )
+        ( Lit: )
+    }
+    );
+
+    #[test]
+    fn test28() {
+        // Raised Commands are executed after transition which raised them is completed.
+        let mut m = Mach21::new(Vec::new());
+        m.execute(&Mach21::Commands::Load).unwrap();
+        assert_eq!(m.state_id(), Mach21::StateId::Ready);
+        assert_eq!(m.inner_context().entries, vec!["load", "enter Loading", "enter Ready", "check"]);
+
+        let mut m = Mach21::new(Vec::new());
+        let error = m.execute(&Mach21::Commands::Bounce).unwrap_err();
+        assert!(matches!(error, Mach21::Error::Overflow{command: Mach21::CommandId::Bounce}));
+        assert_eq!(error.to_string(), "queue of raised commands overflowed by command Bounce");
+        assert_eq!(m.state_id(), Mach21::StateId::Pong);
+
+        let mut m = Mach22::new();
+        assert_eq!(m.execute(&Mach22::Commands::Start).unwrap(), vec![Mach22::RegionId::Motor, Mach22::RegionId::Light]);
+        assert_eq!(m.Motor().id(), Mach22::Motor::StateId::Off);
+        assert_eq!(m.Light().id(), Mach22::Light::StateId::Lit);
+    }
+
+    declare_machine!(
+    Mach23 log{entries: Vec<String>} (Idle)
+    states[Idle, Busy, Working: Busy, Saving: Busy]
+    #[derive(Clone)]
+    commands[Start{job: u32}, Save, Done]
+    pending(2)
+
+    ( Idle :
+        Start(job) [*job > 0] {log.entries.push(format!("start {}", job));} => Working;
+    )
+    ( Busy :
+        defer Start;
+    )
+    ( Working :
+        Save => Saving;
+        Done => Idle;
+    )
+    ( Saving :
+        Done => Idle;
+    )
+    );
+
+    declare_machine!(
+    Mach24
+    #[derive(Clone)]
+    commands[Go, Done]
+
+    region Worker (Busy) {
+        states[Busy, Free]
+        ( Busy: defer Go; Done => Free; )
+        ( Free: Go => Busy; )
+    }
+    region Lamp (Off) {
+        states[Off, On]
+        ( Off: Go => On; )
+        ( On: )
+    }
+    );
+
+    struct Deferrals(::std::rc::Rc<::std::cell::RefCell<Vec<String>>>);
+
+    impl Mach23::Observer for Deferrals {
+        fn on_defer(&mut self, state: Mach23::StateId, command: &Mach23::Commands) {
+            self.0.borrow_mut().push(format!("defer {} {:?}", state, command));
+        }
+        fn on_reject(&mut self, state: Mach23::StateId, command: &Mach23::Commands, error: &Mach23::Error) {
+            self.0.borrow_mut().push(format!("reject {} {:?}: {}", state, command, error));
+        }
+    }
+
+    impl Mach24::Worker::Observer for Deferrals {
+        fn on_defer(&mut self, state: Mach24::Worker::StateId, command: &Mach24::Commands) {
+            self.0.borrow_mut().push(format!("defer {} {:?}", state, command));
+        }
+    }
+
+    #[test]
+    fn test29() {
+        assert_eq!(Mach23::StateId::Working.deferred_commands(), vec![Mach23::CommandId::Start]);
+        assert!(!Mach23::StateId::Idle.is_deferred(Mach23::CommandId::Start));
+        assert!(Mach23::DEFERRALS.iter().any(|deferral| deferral.state == Mach23::StateId::Busy && deferral.command == Mach23::CommandId::Start));
+
+        // Deferred Commands are kept in order of arrival until the buffer is full
+        let events = ::std::rc::Rc::new(::std::cell::RefCell::new(Vec::new()));
+        let mut m = Mach23::new(Vec::new());
+        m.add_observer(Deferrals(events.clone()));
+        m.execute(&Mach23::Commands::Start{job: 1}).unwrap();
+        m.execute(&Mach23::Commands::Start{job: 2}).unwrap();
+        m.execute(&Mach23::Commands::Start{job: 3}).unwrap();
+        let error = m.execute(&Mach23::Commands::Start{job: 4}).unwrap_err();
+        assert!(matches!(error, Mach23::Error::PendingFull{command: Mach23::CommandId::Start}));
+        assert_eq!(error.to_string(), "pending buffer is full, command Start is dropped");
+        assert_eq!(m.pending().iter().cloned().collect::<Vec<_>>(), vec![Mach23::Commands::Start{job: 2}, Mach23::Commands::Start{job: 3}]);
+        assert_eq!(*events.borrow(), vec![
+            "defer Working Start { job: 2 }",
+            "defer Working Start { job: 3 }",
+            "reject Working Start { job: 4 }: pending buffer is full, command Start is dropped",
+        ]);
+
+        // The oldest pending Command is replayed when machine reaches a state which reacts on it
+        m.execute(&Mach23::Commands::Save).unwrap();
+        assert_eq!(m.pending().len(), 2);
+        m.execute(&Mach23::Commands::Done).unwrap();
+        assert_eq!(m.state_id(), Mach23::StateId::Working);
+        assert_eq!(m.inner_context().entries, vec!["start 1", "start 2"]);
+        assert_eq!(m.pending().iter().cloned().collect::<Vec<_>>(), vec![Mach23::Commands::Start{job: 3}]);
+
+        // Failure of replayed Command is reported to observers, not to the caller of execute
+        m.execute(&Mach23::Commands::Start{job: 0}).unwrap();
+        m.execute(&Mach23::Commands::Done).unwrap();
+        m.execute(&Mach23::Commands::Done).unwrap();
+        assert_eq!(m.state_id(), Mach23::StateId::Idle);
+        assert_eq!(m.inner_context().entries, vec!["start 1", "start 2", "start 3"]);
+        assert!(m.pending().is_empty());
+        assert_eq!(events.borrow().last().unwrap(), "reject Idle Start { job: 0 }: no guard passed for command Start in state Idle");
+
+        // Commands which aren't deferred are rejected
+        let mut m = Mach23::new(Vec::new());
+        assert!(m.execute(&Mach23::Commands::Save).is_err());
+        assert!(m.pending().is_empty());
+
+        // Command which no region handled is deferred if any region defers it
+        let events = ::std::rc::Rc::new(::std::cell::RefCell::new(Vec::new()));
+        let mut m = Mach24::new();
+        m.add_Worker_observer(Deferrals(events.clone()));
+        assert_eq!(m.execute(&Mach24::Commands::Go).unwrap(), vec![Mach24::RegionId::Lamp]);
+        assert!(m.pending().is_empty());
+        assert!(events.borrow().is_empty());
+        assert_eq!(m.execute(&Mach24::Commands::Go).unwrap(), vec![]);
+        assert_eq!(m.pending().len(), 1);
+        assert_eq!(*events.borrow(), vec!["defer Busy Go"]);
+        assert_eq!(m.execute(&Mach24::Commands::Done).unwrap(), vec![Mach24::RegionId::Worker]);
+        assert_eq!(m.Worker().id(), Mach24::Worker::StateId::Busy);
+        assert!(m.pending().is_empty());
+    }
+
+    // Future which is pending on the first poll, like I/O which is not ready yet.
+    struct Yield(bool);
+    impl ::std::future::Future for Yield {
+        type Output = ();
+        fn poll(mut self: ::std::pin::Pin<&mut Self>, context: &mut ::std::task::Context) -> ::std::task::Poll<()> {
+            if self.0 {
+                return ::std::task::Poll::Ready(());
+            }
+            self.0 = true;
+            context.waker().wake_by_ref();
+            ::std::task::Poll::Pending
+        }
+    }
+
+    declare_machine!(
+    async Mach25 log{entries: Vec<&'static str>} (Idle{jobs: 0})
+    states[Idle, Busy]
+    commands[Start, Stop]
+
+    ( Idle context{jobs: u32}:
+        Start {context.jobs += 1; Yield(false).await; log.entries.push("start");} => Busy{jobs: context.jobs};
+    )
+    ( Busy context{jobs: u32}:
+        >> {Yield(false).await; log.entries.push("enter Busy");}
+        Stop => Idle{jobs: context.jobs};
+    )
+    );
+
+    #[test]
+    fn test30() {
+        // Execution dropped in reaction callback leaves machine in the old state
+        let mut m = block_on(Mach25::new(Vec::new()));
+        {
+            let mut execution = Box::pin(m.execute(&Mach25::Commands::Start));
+            assert!(poll_once(execution.as_mut()).is_none());
+        }
+        assert_eq!(m.state_id(), Mach25::StateId::Idle);
+        assert!(match *m.current_state() {
+            Mach25::States::Idle{ref context} => context.jobs == 1,
+            _ => false
+        });
+        assert!(m.inner_context().entries.is_empty());
+        block_on(m.execute(&Mach25::Commands::Start)).unwrap();
+        assert_eq!(m.state_id(), Mach25::StateId::Busy);
+        assert_eq!(m.inner_context().entries, vec!["start", "enter Busy"]);
+
+        // Execution dropped in Enter callback leaves machine in the new state
+        block_on(m.execute(&Mach25::Commands::Stop)).unwrap();
+        {
+            let mut execution = Box::pin(m.execute(&Mach25::Commands::Start));
+            assert!(poll_once(execution.as_mut()).is_none());
+            assert!(poll_once(execution.as_mut()).is_none());
+        }
+        assert_eq!(m.state_id(), Mach25::StateId::Busy);
+        assert_eq!(m.inner_context().entries, vec!["start", "enter Busy", "start"]);
+        block_on(m.execute(&Mach25::Commands::Stop)).unwrap();
+        assert_eq!(m.state_id(), Mach25::StateId::Idle);
+    }
+
+    // Neither Clone nor Debug
+    pub struct Channel {
+        peer: String
+    }
+
+    declare_machine!(
+    Mach26 log{entries: Vec<String>} (Idle)
+    states[Idle, Connected, Failed]
+    commands[Connect{peer: String}, Send, Close]
+    error(String => Failed)
+
+    ( Idle:
+        Connect(peer) => Connected{channel: Channel{peer: peer.clone()}, sent: 0};
+    )
+    ( Connected context{channel: Channel; sent: u32}:
+        >> {log.entries.push(format!("connected {}", context.channel.peer));}
+        << {if context.sent == 0 {return Err(String::from("nothing sent"));}}
+        Send {context.sent += 1;} =>;
+        Close {log.entries.push(String::from("close"));} => Idle;
+    )
+    ( Failed: )
+    );
+
+    #[test]
+    fn test31() {
+        // Contexts are not copied when state is left, so they don't have to be Clone or Debug
+        let mut m = Mach26::new(Vec::new());
+        m.execute(&Mach26::Commands::Connect{peer: String::from("peer")}).unwrap();
+        match m.execute(&Mach26::Commands::Close) {
+            Err(Mach26::Error::Leave{state: Mach26::StateId::Connected, ref error}) if error == "nothing sent" => {},
+            r => panic!("unexpected result {:?}", r),
+        }
+        // Failed Leave keeps the state and changes made by callbacks
+        assert!(match *m.current_state() {
+            Mach26::States::Connected{ref context} => context.channel.peer == "peer" && context.sent == 0,
+            _ => false
+        });
+        assert_eq!(m.inner_context().entries, vec!["connected peer", "close"]);
+        m.execute(&Mach26::Commands::Send).unwrap();
+        m.execute(&Mach26::Commands::Close).unwrap();
+        assert_eq!(m.state_id(), Mach26::StateId::Idle);
+    }
+
+    declare_machine!(
+    Mach27 log{entries: Vec<&'static str>}
+    commands[Go, Done]
+    join {log.entries.push("join");}
+
+    region Left (Idle) {
+        states[Idle, Busy, Ready]
+        final[Ready]
+        ( Idle: Go {raise(Commands::Done);} => Busy; )
+        ( Busy: Done {log.entries.push("done");} => Ready; )
+        ( Ready: )
+    }
+    region Right (Idle) {
+        states[Idle, Busy, Broken]
+        final[Broken]
+        error(&'static str => Broken)
+        ( Idle: Go => Busy; )
+        ( Busy: >> {return Err("busy");} )
+        ( Broken: )
+    }
+    );
+
+    #[test]
+    fn test32() {
+        use self::Mach27::{Left, Right, RegionId};
+
+        // Failure of Right doesn't stop Left, which completes its transition and Commands it raised
+        let mut m = Mach27::new(Vec::new());
+        let error = m.execute(&Mach27::Commands::Go).unwrap_err();
+        match error {
+            Mach27::Error::Failed{ref handled, ref errors} => {
+                assert_eq!(*handled, vec![RegionId::Left]);
+                assert_eq!(errors.len(), 1);
+                assert!(matches!(errors[0], Mach27::Error::Right(Right::Error::Enter{state: Right::StateId::Busy, error: "busy"})));
+            },
+            ref error => panic!("unexpected error {:?}", error),
+        }
+        assert_eq!(error.to_string(), "region Right: failed to enter state Busy: \"busy\"");
+        if let Mach27::Error::Failed{ref errors, ..} = error {
+            use std::error::Error;
+            assert!(errors[0].source().is_some());
+            assert!(errors[0].source().and_then(Error::source).is_none());
+        }
+        assert_eq!(m.Left().id(), Left::StateId::Ready);
+        assert_eq!(m.Right().id(), Right::StateId::Broken);
+        assert_eq!(m.inner_context().entries, vec!["done", "join"]);
+    }
+
+    declare_machine!(
+    Mach28 log{entries: Vec<&'static str>, config: bool} (Starting)
+    states[Starting, Failed]
+    commands[Reset]
+    error(&'static str => Failed)
+
+    ( Starting:
+        >> {if !log.config {return Err("no config");} log.entries.push("started");}
+    )
+    ( Failed:
+        Reset => Starting;
+    )
+    );
+
+    declare_machine!(
+    Mach29 log{config: bool}
+    commands[Reset]
+
+    region Left (Idle) {
+        states[Idle]
+        ( Idle: )
+    }
+    region Right (Starting) {
+        states[Starting, Failed]
+        error(&'static str => Failed)
+        ( Starting: >> {if !log.config {return Err("no config");}} )
+        ( Failed: )
+    }
+    );
+
+    #[test]
+    fn test33() {
+        use self::Mach29::{Right, RegionId};
+
+        let m = Mach28::try_new(Vec::new(), true).unwrap();
+        assert_eq!(m.state_id(), Mach28::StateId::Starting);
+        assert_eq!(m.inner_context().entries, vec!["started"]);
+
+        // Failure of the initial Enter is returned by try_new(), new() leaves machine in the error State
+        match Mach28::try_new(Vec::new(), false) {
+            Err(Mach28::Error::Enter{state: Mach28::StateId::Starting, error: "no config"}) => {},
+            r => panic!("unexpected result {:?}", r.map(|m| m.state_id())),
+        }
+        let m = Mach28::new(Vec::new(), false);
+        assert_eq!(m.state_id(), Mach28::StateId::Failed);
+
+        assert!(Mach29::try_new(true).is_ok());
+        match Mach29::try_new(false) {
+            Err(Mach29::Error::Failed{ref handled, ref errors}) if errors.len() == 1 => {
+                assert_eq!(*handled, vec![RegionId::Left]);
+                assert!(matches!(errors[0], Mach29::Error::Right(Right::Error::Enter{state: Right::StateId::Starting, ..})));
+            },
+            r => panic!("unexpected result {:?}", r.map(|_| ())),
+        }
+        assert_eq!(Mach29::new(false).Right().id(), Right::StateId::Failed);
+    }
+
+    declare_machine!(
+    Mach30 (Ping)
+    states[Ping, Pong]
+    commands[Bounce]
+    queue(2)
+
+    ( Ping:
+        >> {raise(Commands::Bounce);}
+        Bounce => Pong;
+    )
+    ( Pong:
+        >> {raise(Commands::Bounce);}
+        Bounce => Ping;
+    )
+    );
+
+    #[test]
+    fn test34() {
+        // Errors of Commands raised by the initial Enter are returned by try_new()
+        match Mach30::try_new() {
+            Err(Mach30::Error::Overflow{command: Mach30::CommandId::Bounce}) => {},
+            r => panic!("unexpected result {:?}", r.map(|m| m.state_id())),
+        }
+        assert_eq!(Mach30::new().state_id(), Mach30::StateId::Ping);
+    }
+
+    // States are named like std types which generated code uses
+    declare_machine!(
+    Mach31 log{entries: ::std::vec::Vec<&'static str>} (Vec)
+    states[Vec, Box, String, Option, Result, Clone: Box]
+    commands[Next, Back]
+    error(&'static str => Result)
+    history(1)
+
+    ( Vec:
+        Next => Clone{size: 1};
+    )
+    ( Box:
+        << {log.entries.push("leave Box");}
+        Back => back;
+    )
+    ( Clone context{size: u32}:
+        Next [context.size > 0] => String;
+    )
+    ( String:
+        Next => Option;
+    )
+    ( Option:
+        >> {return Err("none");}
+    )
+    ( Result:
+        Next => Vec;
+    )
+    );
+
+    #[test]
+    fn test35() {
+        let mut m = Mach31::new(Vec::new());
+        m.execute(&Mach31::Commands::Next).unwrap();
+        assert!(m.is_in(Mach31::StateId::Box));
+        m.execute(&Mach31::Commands::Back).unwrap();
+        assert_eq!(m.state_id(), Mach31::StateId::Vec);
+        m.execute(&Mach31::Commands::Next).unwrap();
+        m.execute(&Mach31::Commands::Next).unwrap();
+        assert!(matches!(m.execute(&Mach31::Commands::Next), Err(Mach31::Error::Enter{state: Mach31::StateId::Option, error: "none"})));
+        assert_eq!(m.state_id(), Mach31::StateId::Result);
+        assert_eq!(m.inner_context().entries, vec!["leave Box", "leave Box"]);
+        assert_eq!(Mach31::TRANSITIONS.len(), 5);
+    }
+
+    declare_machine!(
+    Mach32 (Closed)
+    states[Closed, Open, Broken]
+    commands[Open]
+    error(::std::fmt::Error => Broken)
+    ( Closed:
+        Open => Open;
+    )
+    ( Open:
+        >> {return Err(::std::fmt::Error);}
+    )
+    ( Broken: )
+    );
+
+    #[test]
+    fn test36() {
+        use std::error::Error;
+
+        let mut m = Mach32::new();
+        let err = m.execute(&Mach32::Commands::Open).unwrap_err();
+        assert_eq!(err.to_string(), "failed to enter state Open: Error");
+        assert!(err.source().is_some_and(|source| source.is::<::std::fmt::Error>()));
+        let err = m.execute(&Mach32::Commands::Open).unwrap_err();
+        assert_eq!(err.to_string(), "command Open is not handled in state Broken");
+        assert!(err.source().is_none());
+    }
 }